@@ -0,0 +1,170 @@
+use std::{fs, io::{Seek, SeekFrom, Write}, path::PathBuf};
+use crate::blockchain::{hash_bytes, open, seal};
+
+// Attachments live next to ehr.sqlite, one directory per chain, named by the SHA-256 of their plaintext
+const ATTACHMENT_DIR: &str = "attachments";
+const UPLOAD_DIR: &str = "uploads";
+
+// Raw bytes per chunk when syncing to peers; hex encoding doubles this inside a P2P message
+pub const ATTACHMENT_CHUNK_SIZE: usize = 8192;
+// Largest attachment accepted, from the socket or from a peer
+pub const MAX_ATTACHMENT_SIZE: usize = 64 * 1024 * 1024;
+// Sealing adds a nonce and tag, so the encrypted attachment may take one chunk more than its plaintext
+const MAX_ATTACHMENT_CHUNKS: usize = MAX_ATTACHMENT_SIZE / ATTACHMENT_CHUNK_SIZE + 1;
+
+// Append a chunk of plaintext sent over the socket to an in-progress upload
+pub fn append_upload(upload_id: &str, bytes: &[u8]) -> Result<(), String> {
+    require_safe_name(upload_id)?;
+    let mut path = PathBuf::from(ATTACHMENT_DIR);
+    path.push(UPLOAD_DIR);
+    fs::create_dir_all(&path).map_err(|err| err.to_string())?;
+    path.push(upload_id);
+
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path).map_err(|err| err.to_string())?;
+    let size = file.metadata().map_err(|err| err.to_string())?.len() as usize;
+    if size + bytes.len() > MAX_ATTACHMENT_SIZE {
+        return Err(format!("Attachments are limited to {} bytes", MAX_ATTACHMENT_SIZE));
+    }
+    file.write_all(bytes).map_err(|err| err.to_string())
+}
+
+// Take the plaintext of a completed upload, for encrypting into the chain's store
+pub fn take_upload(upload_id: &str) -> Result<Vec<u8>, String> {
+    require_safe_name(upload_id)?;
+    let mut path = PathBuf::from(ATTACHMENT_DIR);
+    path.push(UPLOAD_DIR);
+    path.push(upload_id);
+
+    let plaintext = fs::read(&path).map_err(|_| format!("Unknown upload: {}", upload_id))?;
+    let _ = fs::remove_file(&path);
+    Ok(plaintext)
+}
+
+pub fn store_attachment(chain_id: &str, plaintext: &[u8], key: &[u8]) -> Result<String, String> {
+    let hash = hash_bytes(plaintext);
+    let sealed = seal(plaintext, key, &attachment_associated_data(chain_id, &hash));
+    write_encrypted_attachment(chain_id, &hash, &sealed)?;
+    Ok(hash)
+}
+
+// Decrypt an attachment and check it still matches the hash it is stored under. Each of the keys the
+// attachment may be sealed with is tried in turn.
+pub fn read_attachment(chain_id: &str, hash: &str, keys: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let sealed = read_encrypted_attachment(chain_id, hash)?;
+    let plaintext = match open_with_any_key(chain_id, hash, &sealed, keys) {
+        Some(plaintext) => plaintext,
+        None => return Err(format!("Unable to decrypt attachment {}", hash))
+    };
+    if hash_bytes(&plaintext) != hash {
+        return Err(format!("Attachment {} does not match its hash", hash));
+    }
+    Ok(plaintext)
+}
+
+pub fn read_encrypted_attachment(chain_id: &str, hash: &str) -> Result<Vec<u8>, String> {
+    let path = attachment_path(chain_id, hash)?;
+    fs::read(path).map_err(|_| format!("Unknown attachment: {}", hash))
+}
+
+pub fn attachment_exists(chain_id: &str, hash: &str) -> bool {
+    match attachment_path(chain_id, hash) {
+        Ok(path) => path.exists(),
+        Err(_) => false
+    }
+}
+
+pub fn list_attachments(chain_id: &str) -> Vec<String> {
+    let mut path = PathBuf::from(ATTACHMENT_DIR);
+    path.push(chain_id);
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_hash(name))
+        .collect()
+}
+
+// Write one chunk of an attachment received from a peer. Once the last chunk arrives the whole
+// attachment is verified against its hash before it is moved into the store.
+pub fn write_attachment_chunk(chain_id: &str, hash: &str, index: usize, total: usize, bytes: &[u8], keys: &[Vec<u8>]) -> Result<bool, String> {
+    // Every chunk but the last is full, so a chunk can only ever land inside the attachment it belongs to
+    if total > MAX_ATTACHMENT_CHUNKS {
+        return Err(format!("Attachment {} is over the {} byte limit", hash, MAX_ATTACHMENT_SIZE));
+    }
+    if index >= total {
+        return Err(format!("Chunk {} is out of range for attachment {}", index, hash));
+    }
+    let last = index + 1 == total;
+    if bytes.is_empty() || bytes.len() > ATTACHMENT_CHUNK_SIZE || (!last && bytes.len() != ATTACHMENT_CHUNK_SIZE) {
+        return Err(format!("Chunk {} of attachment {} has the wrong length", index, hash));
+    }
+    let path = attachment_path(chain_id, hash)?;
+    if path.exists() {
+        return Ok(true);
+    }
+    let partial_path = path.with_extension("part");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+
+    let mut file = fs::OpenOptions::new().create(true).write(true).truncate(index == 0).open(&partial_path).map_err(|err| err.to_string())?;
+    file.seek(SeekFrom::Start((index * ATTACHMENT_CHUNK_SIZE) as u64)).map_err(|err| err.to_string())?;
+    file.write_all(bytes).map_err(|err| err.to_string())?;
+
+    if !last {
+        return Ok(false);
+    }
+
+    let sealed = fs::read(&partial_path).map_err(|err| err.to_string())?;
+    let _ = fs::remove_file(&partial_path);
+    match open_with_any_key(chain_id, hash, &sealed, keys) {
+        Some(plaintext) if hash_bytes(&plaintext) == hash => {
+            write_encrypted_attachment(chain_id, hash, &sealed)?;
+            Ok(true)
+        },
+        _ => Err(format!("Attachment {} failed verification", hash))
+    }
+}
+
+pub fn is_hash(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn write_encrypted_attachment(chain_id: &str, hash: &str, sealed: &[u8]) -> Result<(), String> {
+    let path = attachment_path(chain_id, hash)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    fs::write(path, sealed).map_err(|err| err.to_string())
+}
+
+fn attachment_path(chain_id: &str, hash: &str) -> Result<PathBuf, String> {
+    require_safe_name(chain_id)?;
+    if !is_hash(hash) {
+        return Err(format!("Invalid attachment hash: {}", hash));
+    }
+    let mut path = PathBuf::from(ATTACHMENT_DIR);
+    path.push(chain_id);
+    path.push(hash);
+    Ok(path)
+}
+
+fn open_with_any_key(chain_id: &str, hash: &str, sealed: &[u8], keys: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let associated_data = attachment_associated_data(chain_id, hash);
+    keys.iter().find_map(|key| open(sealed, key, &associated_data).ok())
+}
+
+fn attachment_associated_data(chain_id: &str, hash: &str) -> Vec<u8> {
+    format!("{}:{}", chain_id, hash).into_bytes()
+}
+
+// Ids from the socket or peers become file names, so keep them to a safe character set
+fn require_safe_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == UPLOAD_DIR || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid identifier: {}", name));
+    }
+    Ok(())
+}
@@ -1,18 +1,37 @@
 use local_ip_address::local_ip;
 use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
-use openssl::rsa::Rsa;
-use serde_json::{from_str, to_string, to_value, Map, Value};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::{Signer, Verifier};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use serde_json::{from_str, from_value, to_string, to_value, Map, Value};
 use tokio::sync::mpsc::{Receiver, Sender};
 use chrono::Utc;
 use openssl::sha::Sha256;
-use openssl::symm::{Cipher, encrypt, decrypt};
+use openssl::symm::{Cipher, decrypt, decrypt_aead, encrypt_aead};
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use rustc_serialize::hex::{ToHex, FromHex};
 use uuid::Uuid;
-use crate::database::{chain_exists, fetch_all_blocks, fetch_all_transactions, fetch_chains, fetch_last_block, fetch_record, get_key_pair, get_shared_key, insert_block, insert_chain, insert_new_shared_key, insert_shared_key, is_chain_active, set_chain_active, update_block, KeyPair};
-use crate::network::P2PRequest;
+use crate::database::{chain_exists, fetch_all_blocks, fetch_attachment_key, fetch_block_hash, fetch_chain_ids, fetch_chains, fetch_date_of_birth, fetch_directory_entry, fetch_pending_keys, delete_pending_keys, fetch_genesis_key, fetch_last_block, fetch_projected_providers, fetch_projected_records, fetch_provider_public_keys, fetch_provider_roles_by_ip, fetch_provider_roles_by_key, fetch_provider_roles_by_node_id, fetch_record_keys, fetch_record_revisions, fetch_stale_projection_chain_ids, fetch_epoch_keys, get_current_epoch, is_chain_integrity_failed, set_chain_integrity_failed, get_epoch_key, get_key_pair, get_shared_key, insert_attachment_key, insert_block, replace_projection, insert_chain, insert_shared_key, is_chain_active, set_chain_active, KeyPair, RESTRICTED_KIND};
+use crate::discovery::discovered_providers;
+use crate::directory::{search_entries, sign_entry, store_entry};
+use crate::network::{catch_up_chains, catch_up_progress, fetch_remote_public_key, p2p_error, pending_outbox, sync_directory, P2PError, P2PErrorCode, P2PRequest};
+use crate::attachment::{append_upload, attachment_exists, read_attachment, store_attachment, take_upload};
+use crate::payload::{fields_from_parameters, integer_parameter, is_unopened, string_parameter, validate_content, AddProviderFields, AddRecordFields, AmendRecordFields, AttachmentReference, BlockData, ConditionStatus, GenesisFields, MedicationStatus, ProviderRole, RecordContent, RecordDetails, RecordGrant, RecordKey, RemoveProviderFields, RetractRecordFields, SealedRecord, ShareRecordsFields};
+
+// Version 0 is the legacy undelimited hash format, version 1 the length-prefixed canonical header,
+// version 2 adds the key epoch to the canonical header
+const CURRENT_BLOCK_VERSION: i64 = 2;
+
+// Default number of attachment bytes returned by one get_attachment call
+const SOCKET_ATTACHMENT_WINDOW: usize = 65536;
+
+const GCM_DATA_PREFIX: &str = "v2:";
+const GCM_NONCE_LENGTH: usize = 12;
+const GCM_TAG_LENGTH: usize = 16;
 
 // Define the structure for a block
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,13 +43,14 @@ pub struct Block {
     pub previous_hash: String,
     pub hash: String,
     pub provider_key: String,
-    pub data_hash: String
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BlockData {
-    pub action: String,
-    pub fields: serde_json::Map<String, Value>
+    pub data_hash: String,
+    #[serde(default)]
+    pub signature: String,
+    #[serde(default)]
+    pub version: i64,
+    // Which of the chain's shared keys the data is encrypted with; a new epoch starts whenever a provider is removed
+    #[serde(default)]
+    pub key_epoch: i64
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +59,40 @@ pub struct Chain {
     pub first_name: String,
     pub last_name: String,
     pub date_of_birth: String,
+    // The chain failed the integrity check at startup, so it isn't synced with other providers
+    pub integrity_failed: bool,
+}
+
+// Result of checking a single stored block during chain verification
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockVerification {
+    pub id: i64,
+    pub version: i64,
+    pub hash_valid: bool,
+    pub link_valid: bool,
+    pub id_valid: bool,
+    pub data_valid: bool,
+    // One of "valid", "invalid" or "unsigned" (blocks written before signing was introduced)
+    pub signature: String,
+    pub ok: bool
+}
+
+// One entry in a record's revision history
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordRevision {
+    pub block_id: i64,
+    pub timestamp: i64,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<RecordDetails>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,53 +109,68 @@ pub struct BlockchainResponse {
 }
 
 pub async fn initialize_blockchain_thread(mut receiver: Receiver<String>, sender_to_socket: Sender<String>, sender_to_p2p: Sender<String>){
+    // Pull the blocks added while we were offline, then tell the other providers if this node's address has changed
+    // since it was last recorded. The address update is appended after catching up, so it extends the latest head.
+    // Directory entries published while we were offline are fetched last.
+    let sender = sender_to_p2p.clone();
+    tokio::spawn(async move {
+        catch_up_chains().await;
+        update_own_addresses(&sender).await;
+        sync_directory().await;
+    });
+
     // Receive messages from the socket thread
     loop {
         if let Some(msg) = receiver.recv().await {
             let blockchain_request: BlockchainRequest = from_str(&msg).unwrap();
             if blockchain_request.sender == "socket" {
-                match blockchain_request.action.as_str() {
-                    "get_chains" => {
-                        let response = get_chains();
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
+                let parameters = blockchain_request.parameters;
+                let response = match blockchain_request.action.as_str() {
+                    "get_chains" => get_chains(),
+                    "create_chain" => create_chain(parameters),
+                    "get_patient_info" => match string_parameter(&parameters, "id") {
+                        Ok(id) => get_patient_info(id),
+                        Err(err) => error_response(err)
                     },
-                    "create_chain" => {
-                        // Need to get parameters from socket message here and use them
-                        let response = create_chain(blockchain_request.parameters);
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
+                    "get_record" => match (string_parameter(&parameters, "id"), integer_parameter(&parameters, "block_id")) {
+                        (Ok(id), Ok(block_id)) => get_record(id, block_id, parameters.get("history").and_then(Value::as_bool).unwrap_or(false)).await,
+                        (Err(err), _) | (_, Err(err)) => error_response(err)
                     },
-                    "get_patient_info" => {
-                        let response = get_patient_info(blockchain_request.parameters.get("id").unwrap().as_str().unwrap().to_string());
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
+                    "add_provider" => add_provider(parameters, &sender_to_p2p).await,
+                    "add_record" => add_record(parameters, &sender_to_p2p).await,
+                    "remove_provider" => remove_provider(parameters, &sender_to_p2p).await,
+                    "update_provider_address" => update_provider_address(parameters, &sender_to_p2p).await,
+                    "amend_record" => amend_record(parameters, &sender_to_p2p).await,
+                    "retract_record" => retract_record(parameters, &sender_to_p2p).await,
+                    "share_record" => share_record(parameters, &sender_to_p2p).await,
+                    "upload_attachment" => upload_attachment(parameters),
+                    "get_attachment" => get_attachment(parameters),
+                    "get_sync_progress" => BlockchainResponse{ok: true, data: to_value(catch_up_progress()).unwrap()},
+                    "publish_directory_entry" => publish_directory_entry(parameters, &sender_to_p2p).await,
+                    "search_directory" => search_directory(parameters),
+                    "discover_providers" => BlockchainResponse{ok: true, data: to_value(discovered_providers()).unwrap()},
+                    "get_outbox" => match pending_outbox() {
+                        Ok(peers) => BlockchainResponse{ok: true, data: to_value(peers).unwrap()},
+                        Err(err) => error_response(err)
                     },
-                    "get_record" => {
-                        let response = get_record(blockchain_request.parameters.get("id").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("block_id").unwrap().as_i64().unwrap()).await;
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
-                    }
-                    "add_provider" => {
-                        let response = add_provider(blockchain_request.parameters, &sender_to_p2p).await;
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
+                    "verify_chain" => match string_parameter(&parameters, "id") {
+                        Ok(id) => verify_chain(id),
+                        Err(err) => error_response(err)
                     },
-                    "add_record" => {
-                        let response = add_record(blockchain_request.parameters, &sender_to_p2p).await;
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
-                    }
-                    "remove_provider" => {
-                        let response = remove_provider(blockchain_request.parameters, &sender_to_p2p).await;
-                        sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
-                    }
-                    _ => {}
-                }
+                    _ => error_response(format!("Unknown action: {}", blockchain_request.action))
+                };
+                sender_to_socket.send(to_string(&response).unwrap()).await.unwrap();
             } else if blockchain_request.sender == "p2p" {
                 // TODO: Add p2p request/responses in future enhancements
-                match blockchain_request.action.as_str() {
-                    _ => {}
-                }
             }
         }
     }
 }
 
+pub fn error_response(message: String) -> BlockchainResponse {
+    BlockchainResponse{ok: false, data: Value::String(message)}
+}
+
 pub fn get_chains() -> BlockchainResponse {
     match fetch_chains() {
         Ok(chains) => {
@@ -113,217 +182,470 @@ pub fn get_chains() -> BlockchainResponse {
 }
 
 pub fn get_patient_info(id: String) -> BlockchainResponse {
-    let shared_key_vec = match get_shared_key(id.clone()){
-        Ok(key) => key,
-        Err(_) => return BlockchainResponse{ok: false, data: Value::Null}
-    };
-    let shared_key = shared_key_vec.as_slice();
-    if !is_chain_active(id.clone()).unwrap(){
+    if !is_chain_active(id.clone()).unwrap_or(false){
         return BlockchainResponse{ok: false, data: Value::Null};
     }
-    
-    match fetch_all_transactions(id){
-        Ok(blocks) => {
-            let mut data: Map<String, Value> = Map::default();
-
-            // For now, records are of shape: date, subject, record_id
-            let mut records:Vec<(Value, Value, Value)> = vec![];
-
-            // For now, providers are of shape: name, ip_address
-            let mut providers: Vec<(Value, Value)> = vec![];
-            
-            for (timestamp, block_id, encrypted_data) in blocks {
-                let block_data_result = decrypt_data(&encrypted_data, shared_key);
-                match block_data_result{
-                    Ok(block_data) => {
-                        match block_data.action.as_str() {
-                            "genesis" => {
-                                data.insert("date_of_birth".to_string(), block_data.fields.get("date_of_birth").unwrap().clone());
-                            },
-                            "add-provider" => {
-                                providers.push((block_data.fields.get("name").unwrap().clone(), block_data.fields.get("ip").unwrap().clone()));
-                            }
-                            "add-record" => {
-                                records.push((to_value(timestamp).unwrap(), block_data.fields.get("subject").unwrap().clone(), to_value(block_id).unwrap()));
-                            }
-                            "remove-provider" => {
-                                providers.retain(|(_, ip)| ip.as_str().unwrap().to_string() != block_data.fields.get("ip").unwrap().as_str().unwrap().to_string())
-                            }
-                            _ => {}
-                        }
-                    },
-                    Err(_) => {
-                        return BlockchainResponse{ ok: false, data: Value::Null }
-                    },
-                }
-            }
 
-            data.insert("providers".to_string(), to_value(providers).unwrap());
-            data.insert("records".to_string(), to_value(records).unwrap());
+    // Read from the patient-state projection rather than replaying the chain
+    let (date_of_birth, providers, projected_records) = match (fetch_date_of_birth(id.clone()), fetch_projected_providers(id.clone()), fetch_projected_records(id.clone())) {
+        (Ok(date_of_birth), Ok(providers), Ok(records)) => (date_of_birth, providers, records),
+        _ => return BlockchainResponse{ok: false, data: Value::Null}
+    };
 
-            let patient_blocks_string = to_value(&data).unwrap();
-            BlockchainResponse{ok: true, data: patient_blocks_string}
-        },
-        Err(_) => {BlockchainResponse{ok: false, data: Value::Null}}
+    let mut data: Map<String, Value> = Map::default();
+    data.insert("date_of_birth".to_string(), to_value(date_of_birth).unwrap());
+
+    // For now, records are of shape: date, subject, record_id, status, kind
+    let records: Vec<(i64, &str, i64, &str, &str)> = projected_records.iter()
+        .filter(|record| record.kind != RESTRICTED_KIND)
+        .map(|record| (record.timestamp, record.subject.as_str(), record.record_id, record.status.as_str(), record.kind.as_str()))
+        .collect();
+
+    // Derived clinical lists only include the current version of records that have not been retracted
+    let mut medications: Vec<Value> = vec![];
+    let mut allergies: Vec<Value> = vec![];
+    let mut conditions: Vec<Value> = vec![];
+    let mut immunizations: Vec<Value> = vec![];
+    for record in projected_records.iter().filter(|record| record.status != "retracted") {
+        let details = match &record.details {
+            Some(details) => details,
+            None => continue
+        };
+        let list = match details {
+            RecordDetails::Medication(medication) if medication.status == MedicationStatus::Active => &mut medications,
+            RecordDetails::Allergy(_) => &mut allergies,
+            RecordDetails::Condition(condition) if condition.status == ConditionStatus::Active => &mut conditions,
+            RecordDetails::Immunization(_) => &mut immunizations,
+            _ => continue
+        };
+        let mut entry = to_value(details).unwrap();
+        entry["record_id"] = to_value(record.record_id).unwrap();
+        list.push(entry);
     }
+
+    // For now, providers are of shape: name, ip_address, role, node_id
+    data.insert("providers".to_string(), to_value(&providers).unwrap());
+    data.insert("records".to_string(), to_value(records).unwrap());
+    data.insert("medications".to_string(), to_value(medications).unwrap());
+    data.insert("allergies".to_string(), to_value(allergies).unwrap());
+    data.insert("conditions".to_string(), to_value(conditions).unwrap());
+    data.insert("immunizations".to_string(), to_value(immunizations).unwrap());
+    data.insert("integrity_failed".to_string(), to_value(is_chain_integrity_failed(&id).unwrap_or(false)).unwrap());
+
+    BlockchainResponse{ok: true, data: Value::Object(data)}
 }
 
+// The other providers on a chain as (node_id, ip_address). We are recognised by our node id, or by
+// our address for providers added before node ids existed.
 pub fn get_active_providers(id: String) -> Vec<(String, String)>{
-    let shared_key_vec = get_shared_key(id.clone()).unwrap();
-    let shared_key = shared_key_vec.as_slice();
-    
-    match fetch_all_transactions(id){
-        Ok(blocks) => {
-            // For now, providers are of shape: name, ip_address
-            let mut providers: Vec<(String, String)> = vec![];
-            
-            for (_, _, encrypted_data) in blocks {
-                let block_data_result = decrypt_data(&encrypted_data, shared_key);
-                match block_data_result {
-                    Ok(block_data) => {
-                        match block_data.action.as_str() {
-                            "add-provider" => {
-                                providers.push((block_data.fields.get("name").unwrap().clone().as_str().unwrap().to_string(), block_data.fields.get("ip").unwrap().clone().as_str().unwrap().to_string()));
-                            }
-                            "remove-provider" => {
-                                providers.retain(|(_, ip)| *ip != block_data.fields.get("ip").unwrap().as_str().unwrap().to_string())
-                            }
-                            _ => {}
-                        }
-                    },
-                    Err(_) => return vec![],
-                }
-            }
-            providers
-        },
-        Err(_) => {vec![]}
+    let my_node_id = my_node_id();
+    let my_ip = local_ip().map(|ip| ip.to_string()).unwrap_or_default();
+    fetch_projected_providers(id).unwrap_or_default().into_iter()
+        .filter(|(_, ip, _, node_id)| if node_id.is_empty() { *ip != my_ip } else { *node_id != my_node_id })
+        .map(|(_, ip, _, node_id)| (node_id, ip))
+        .collect()
+}
+
+// A node is identified by the SHA-256 of its DER public key, which stays the same when its address changes
+pub fn my_node_id() -> String {
+    key_id(&get_key_pair().unwrap().expect("Expected KeyPair").public_key)
+}
+
+// The creator of a chain is always its owner; everyone else has the highest role they have been granted
+fn provider_role(chain_id: &str, provider_key: &str) -> Option<ProviderRole> {
+    if fetch_genesis_key(chain_id.to_string()).map(|key| key == provider_key).unwrap_or(false) {
+        return Some(ProviderRole::Owner);
     }
+    fetch_provider_roles_by_key(chain_id.to_string(), provider_key.to_string()).unwrap_or_default()
+        .iter()
+        .filter_map(|role| ProviderRole::parse(role))
+        .max()
 }
 
-pub async fn get_record(chain_id: String, block_id: i64) -> BlockchainResponse {
-    let shared_key_vec = get_shared_key(chain_id.clone()).unwrap();
-    let shared_key = shared_key_vec.as_slice();
+// The same, for a peer known only by the node id it proved over TLS
+pub fn node_role(chain_id: &str, node_id: &str) -> Option<ProviderRole> {
+    if fetch_genesis_key(chain_id.to_string()).map(|key| key_id(&key) == node_id).unwrap_or(false) {
+        return Some(ProviderRole::Owner);
+    }
+    fetch_provider_roles_by_node_id(chain_id.to_string(), node_id.to_string()).unwrap_or_default()
+        .iter()
+        .filter_map(|role| ProviderRole::parse(role))
+        .max()
+}
 
-    match fetch_record(chain_id, block_id) {
-        Ok(record) => {
-            let block_data_result = decrypt_data(&record.1, shared_key);
-            match block_data_result{
-                Ok(mut block_data) => {
-                    block_data.fields.insert("timestamp".to_string(), to_value(record.0).unwrap());
-                    return BlockchainResponse{ok: true, data: to_value(block_data.fields).unwrap()}
-                },
-                Err(_) => {return BlockchainResponse{ok: false, data: Value::Null};}
+// Checked against our own key before creating a block, and against the signer's key before ingesting one
+fn check_permission(chain_id: &str, provider_key: &str, data: &BlockData) -> Result<(), String> {
+    let role = match provider_role(chain_id, provider_key) {
+        Some(role) => role,
+        None => return Err(format!("Permission denied: not a provider on chain {}", chain_id))
+    };
+    let target_roles = match data {
+        BlockData::AddProvider(fields) => vec![fields.role.as_str().to_string()],
+        BlockData::RemoveProvider(fields) if fields.node_id.is_empty() => fetch_provider_roles_by_ip(chain_id.to_string(), fields.ip.clone()).unwrap_or_default(),
+        BlockData::RemoveProvider(fields) => fetch_provider_roles_by_node_id(chain_id.to_string(), fields.node_id.clone()).unwrap_or_default(),
+        BlockData::UpdateProviderAddress(fields) => {
+            // Any provider may record its own new address
+            if fields.node_id == key_id(provider_key) {
+                return Ok(());
             }
+            fetch_provider_roles_by_node_id(chain_id.to_string(), fields.node_id.clone()).unwrap_or_default()
         },
-        Err(_) => {return BlockchainResponse{ok: false, data: Value::Null};}
+        _ => vec![]
+    };
+    let target_role = target_roles.iter().filter_map(|role| ProviderRole::parse(role)).max().unwrap_or_default();
+    if !role.can_append(data, target_role) {
+        return Err(format!("Permission denied: {} cannot {}", role.as_str(), data.action()));
     }
+    Ok(())
 }
 
-pub async fn add_record(parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
-    let chain_id = parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
-    let shared_key_vec = get_shared_key(chain_id.clone()).unwrap();
-    let shared_key = shared_key_vec.as_slice();
-    let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+pub async fn get_record(chain_id: String, block_id: i64, include_history: bool) -> BlockchainResponse {
+    let mut revisions = match record_revisions(&chain_id, block_id) {
+        Ok(revisions) => revisions,
+        Err(err) => return error_response(err)
+    };
+    // Attachment keys stay with the daemon; clients read attachments through get_attachment
+    for attachment in revisions.iter_mut().flat_map(|revision| revision.attachments.iter_mut()) {
+        attachment.key.clear();
+    }
+    let status = match record_status(&revisions) {
+        Some(status) => status,
+        None => return error_response(format!("Block {} is not a record", block_id))
+    };
 
-    let data = BlockData{action:"add-record".to_string(), fields: parameters.clone()};
-    let encrypted_data = encrypt_data(&data, &shared_key);
-    let hashed_data = hash_data(&data);
+    // The latest add or amend holds the current version; the record keeps the date it was first charted
+    let current = match revisions.iter().rev().find(|revision| revision.subject.is_some()) {
+        Some(current) => current,
+        None => return error_response(format!("Record {} is restricted", block_id))
+    };
+    let mut data: Map<String, Value> = Map::default();
+    data.insert("subject".to_string(), to_value(&current.subject).unwrap());
+    data.insert("text".to_string(), to_value(&current.text).unwrap());
+    data.insert("kind".to_string(), to_value(record_kind(&current.details)).unwrap());
+    if let Some(details) = &current.details {
+        data.insert("details".to_string(), to_value(details).unwrap());
+    }
+    data.insert("attachments".to_string(), to_value(&current.attachments).unwrap());
+    data.insert("timestamp".to_string(), to_value(revisions[0].timestamp).unwrap());
+    data.insert("status".to_string(), to_value(status).unwrap());
+    if include_history {
+        data.insert("revisions".to_string(), to_value(&revisions).unwrap());
+    }
 
-    let last_block = get_last_block(chain_id.clone());
-    let mut add_record_block = Block{
-        chain_id: chain_id,
-        id: last_block.id + 1,
-        timestamp: Utc::now().timestamp(), 
-        data: encrypted_data,
-        previous_hash: last_block.hash,
-        hash: "".to_string(),
-        provider_key: my_key.public_key,
-        data_hash: hashed_data
+    BlockchainResponse{ok: true, data: Value::Object(data)}
+}
+
+pub fn verify_chain(chain_id: String) -> BlockchainResponse {
+    let blocks = match fetch_all_blocks(chain_id.clone()) {
+        Ok(blocks) => blocks,
+        Err(_) => return BlockchainResponse{ok: false, data: Value::Null}
     };
 
-    let hash = hash_block(&add_record_block);
-    add_record_block.hash = hash;
+    let mut reports: Vec<BlockVerification> = vec![];
+    let mut previous_hash = 0.to_string();
+
+    for (index, block) in blocks.iter().enumerate() {
+        let hash_valid = hash_block(block) == block.hash;
+        let link_valid = block.previous_hash == previous_hash;
+        let id_valid = block.id == index as i64;
+        let plaintext = block_key(block).ok().and_then(|key| decrypt_stored_plaintext(&block.data, &key, &block.chain_id, block.id).ok());
+        let data_valid = match plaintext {
+            Some(plaintext) => hash_bytes(&plaintext) == block.data_hash,
+            None => false
+        };
+        let signature = if block.signature.is_empty() {
+            "unsigned"
+        } else if verify_block_signature(block) {
+            "valid"
+        } else {
+            "invalid"
+        };
+
+        reports.push(BlockVerification{
+            id: block.id,
+            version: block.version,
+            hash_valid,
+            link_valid,
+            id_valid,
+            data_valid,
+            signature: signature.to_string(),
+            ok: hash_valid && link_valid && id_valid && data_valid && signature != "invalid"
+        });
+        previous_hash = block.hash.clone();
+    }
 
-    let _ = insert_block(&add_record_block);
-    
-    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "add-record".to_string(), parameters}).unwrap()).await;
+    let mut data: Map<String, Value> = Map::default();
+    data.insert("chain_id".to_string(), to_value(chain_id).unwrap());
+    data.insert("ok".to_string(), to_value(!reports.is_empty() && reports.iter().all(|report| report.ok)).unwrap());
+    data.insert("blocks".to_string(), to_value(reports).unwrap());
 
-    BlockchainResponse{ok: true, data: Value::Null}
+    BlockchainResponse{ok: true, data: Value::Object(data)}
 }
 
-pub async fn add_provider(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
-    let chain_id = parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
+// Run at startup so tampering or corruption in the local database is reported before anyone reads from it
+pub fn verify_all_chains() {
+    let chain_ids = match fetch_chain_ids() {
+        Ok(ids) => ids,
+        Err(_) => return
+    };
+
+    // The result is recorded per chain, so a chain that failed is shown as such and isn't synced until it passes
+    for chain_id in chain_ids {
+        let response = verify_chain(chain_id.clone());
+        let chain_ok = response.ok && response.data.get("ok").and_then(Value::as_bool).unwrap_or(false);
+        if let Err(err) = set_chain_integrity_failed(&chain_id, !chain_ok) {
+            eprintln!("Unable to record the integrity check of chain {}: {}", chain_id, err);
+        }
+        if chain_ok {
+            continue;
+        }
+
+        eprintln!("Integrity check failed for chain {}", chain_id);
+        if let Some(Value::Array(reports)) = response.data.get("blocks") {
+            for report in reports.iter().filter(|report| report.get("ok") != Some(&Value::Bool(true))) {
+                eprintln!("  {}", report);
+            }
+        }
+    }
+}
+
+pub fn rebuild_projection(chain_id: &str) -> Result<(), String> {
+    let blocks = match fetch_all_blocks(chain_id.to_string()) {
+        Ok(blocks) => blocks,
+        Err(_) => return Err(format!("Unknown chain: {}", chain_id))
+    };
+
+    let mut decrypted: Vec<(Block, BlockData)> = vec![];
+    for block in blocks {
+        let data = decrypt_data(&block.data, &block_key(&block)?, chain_id, block.id)?;
+        decrypted.push((block, data));
+    }
 
-    let shared_key_vec = get_shared_key(chain_id.clone()).unwrap();
-    let shared_key = shared_key_vec.as_slice();
+    // Grants can come after the record they are for, so collect every record key before opening any records
+    let mut keys_by_record: HashMap<i64, Vec<RecordKey>> = HashMap::new();
+    for (block, data) in &decrypted {
+        match data {
+            BlockData::AddRecord(AddRecordFields{ sealed: Some(sealed), .. }) => keys_by_record.entry(block.id).or_default().extend(sealed.keys.iter().cloned()),
+            BlockData::ShareRecords(fields) => {
+                for grant in &fields.grants {
+                    keys_by_record.entry(grant.block_id).or_default().push(RecordKey{ recipient: grant.recipient.clone(), key: grant.key.clone() });
+                }
+            },
+            _ => {}
+        }
+    }
     let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    let decrypted: Vec<(Block, BlockData)> = decrypted.into_iter()
+        .map(|(block, data)| {
+            let opened = open_block_data(&block, data, &my_key, &|record_id| keys_by_record.get(&record_id).cloned().unwrap_or_default());
+            (block, opened)
+        })
+        .collect();
+    replace_projection(chain_id.to_string(), &decrypted).map_err(|err| err.to_string())
+}
 
-    let data = BlockData{action:"add-provider".to_string(), fields: parameters.clone()};
-    let encrypted_data = encrypt_data(&data, &shared_key);
-    let hashed_data = hash_data(&data);
+// Run at startup to project chains stored before the projection existed, or left behind by an interrupted write
+pub fn rebuild_stale_projections() {
+    let chain_ids = match fetch_stale_projection_chain_ids() {
+        Ok(ids) => ids,
+        Err(_) => return
+    };
+    for chain_id in chain_ids {
+        if let Err(err) = rebuild_projection(&chain_id) {
+            eprintln!("Unable to rebuild projection for chain {}: {}", chain_id, err);
+        }
+    }
+}
 
-    let last_block = get_last_block(chain_id.clone());
-    let mut add_provider_block = Block{
-        chain_id: chain_id,
-        id: last_block.id + 1,
-        timestamp: Utc::now().timestamp(), 
-        data: encrypted_data,
-        previous_hash: last_block.hash,
-        hash: "".to_string(),
-        provider_key: my_key.public_key,
-        data_hash: hashed_data
+pub async fn add_record(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    let mut fields: AddRecordFields = match fields_from_parameters(&parameters) {
+        Ok(fields) => fields,
+        Err(err) => return error_response(err)
+    };
+    if let Err(err) = validate_content(&fields.subject, &fields.details, &fields.attachments) {
+        return error_response(err);
+    }
+    if let Err(err) = fill_attachment_keys(&chain_id, &mut fields.attachments) {
+        return error_response(err);
+    }
+    let readers = match record_readers(&chain_id, &parameters) {
+        Ok(readers) => readers,
+        Err(err) => return error_response(err)
     };
 
-    let hash = hash_block(&add_provider_block);
-    add_provider_block.hash = hash;
+    // Every record gets its own data key, wrapped for each provider allowed to read it
+    let data_key = generate_shared_key();
+    let mut keys: Vec<RecordKey> = vec![];
+    for public_key in &readers {
+        match wrap_key(&data_key, public_key) {
+            Ok(key) => keys.push(RecordKey{ recipient: key_id(public_key), key }),
+            Err(err) => return error_response(err)
+        }
+    }
+    let selected_readers = parameters.contains_key("readers");
+    let reader_ids: Vec<String> = keys.iter().map(|record_key| record_key.recipient.clone()).collect();
+    let content = RecordContent{ subject: fields.subject, text: fields.text, details: fields.details, attachments: fields.attachments };
+    let data = BlockData::AddRecord(AddRecordFields{
+        subject: String::new(), text: String::new(), details: None, attachments: vec![],
+        sealed: Some(SealedRecord{ content: seal_record(&chain_id, &content, &data_key), keys, selected_readers })
+    });
+    if let Err(err) = append_block(&chain_id, &data) {
+        return error_response(err);
+    }
 
-    let _ = insert_block(&add_provider_block);
-    parameters.insert("shared_key".to_string(), from_str(format!("\"{}\"", shared_key_vec.to_hex().as_str()).as_str()).unwrap());
-    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "add-provider".to_string(), parameters}).unwrap()).await;
+    // Only the record's readers get its attachment keys, so the network task only sends them the attachments
+    parameters.insert("readers".to_string(), to_value(reader_ids).unwrap());
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "add-record".to_string(), parameters}).unwrap()).await;
 
     BlockchainResponse{ok: true, data: Value::Null}
 }
 
-pub async fn remove_provider(parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
-    let chain_id = parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
-
-    let shared_key_vec = get_shared_key(chain_id.clone()).unwrap();
-    let shared_key = shared_key_vec.as_slice();
+// Public keys a new record's data key is wrapped to: ours, plus either the providers listed by
+// node id or address in readers or, by default, every provider on the chain
+fn record_readers(chain_id: &str, parameters: &Map<String, Value>) -> Result<Vec<String>, String> {
     let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    let mut readers = vec![my_key.public_key];
+
+    let provider_keys = fetch_provider_public_keys(chain_id.to_string()).unwrap_or_default();
+    let selected: Vec<String> = match parameters.get("readers") {
+        Some(Value::Array(providers)) => {
+            let mut selected = vec![];
+            for provider in providers {
+                let provider = match provider.as_str() {
+                    Some(provider) => provider,
+                    None => return Err("Field readers must be a list of provider node ids or addresses".to_string())
+                };
+                selected.extend(provider_public_keys(&provider_keys, provider)?);
+            }
+            selected
+        },
+        Some(_) => return Err("Field readers must be a list of provider node ids or addresses".to_string()),
+        None => provider_keys.into_iter().map(|(_, public_key)| public_key).collect()
+    };
+    for public_key in selected {
+        if !readers.contains(&public_key) {
+            readers.push(public_key);
+        }
+    }
+    Ok(readers)
+}
 
-    let data = BlockData{action:"remove-provider".to_string(), fields: parameters.clone()};
-    let encrypted_data = encrypt_data(&data, &shared_key);
-    let hashed_data = hash_data(&data);
+// A provider is named by node id, or by its current address
+fn provider_public_keys(provider_keys: &[(String, String)], provider: &str) -> Result<Vec<String>, String> {
+    let keys: Vec<String> = provider_keys.iter()
+        .filter(|(ip, public_key)| key_id(public_key) == provider || ip == provider)
+        .map(|(_, public_key)| public_key.clone())
+        .collect();
+    if keys.is_empty() {
+        return Err(format!("Unknown provider: {}", provider));
+    }
+    Ok(keys)
+}
 
-    let last_block = get_last_block(chain_id.clone());
-    let mut remove_provider_block = Block{
-        chain_id: chain_id.clone(),
-        id: last_block.id + 1,
-        timestamp: Utc::now().timestamp(), 
-        data: encrypted_data,
-        previous_hash: last_block.hash,
-        hash: "".to_string(),
-        provider_key: my_key.public_key,
-        data_hash: hashed_data
+pub async fn add_provider(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    // A provider picked from the directory is added with the name, address and key they published
+    if let Some(node_id) = parameters.get("directory_entry").and_then(Value::as_str).map(str::to_string) {
+        let entry = match fetch_directory_entry(&node_id) {
+            Ok(Some(entry)) => entry,
+            _ => return error_response(format!("No directory entry for node {}", node_id))
+        };
+        if !parameters.contains_key("ip") {
+            let ip = match entry.addresses.first() {
+                Some(ip) => ip.clone(),
+                None => return error_response(format!("The directory entry for {} lists no address", entry.name))
+            };
+            parameters.insert("ip".to_string(), to_value(ip).unwrap());
+        }
+        parameters.entry("name").or_insert(to_value(&entry.name).unwrap());
+        parameters.insert("public_key".to_string(), to_value(&entry.public_key).unwrap());
+    }
+    // The provider's key can be given directly, otherwise it is asked for from the node at their address
+    if !parameters.contains_key("public_key") {
+        let ip = match string_parameter(&parameters, "ip") {
+            Ok(ip) => ip,
+            Err(err) => return error_response(err)
+        };
+        let public_key = if local_ip().map(|my_ip| my_ip.to_string() == ip).unwrap_or(false) {
+            get_key_pair().unwrap().expect("Expected KeyPair").public_key
+        } else {
+            match fetch_remote_public_key(ip).await {
+                Ok(public_key) => public_key,
+                Err(err) => return error_response(err)
+            }
+        };
+        parameters.insert("public_key".to_string(), to_value(public_key).unwrap());
+    }
+    // A provider picked from discovery is named by node id too, so a different node now at that address is refused
+    if let (Some(node_id), Some(public_key)) = (parameters.get("node_id").and_then(Value::as_str), parameters.get("public_key").and_then(Value::as_str)) {
+        if key_id(public_key) != node_id {
+            return error_response(format!("The provider at this address is not node {}", node_id));
+        }
+    }
+    let fields: AddProviderFields = match fields_from_parameters(&parameters) {
+        Ok(fields) => fields,
+        Err(err) => return error_response(err)
     };
+    let public_key = fields.public_key.clone();
+    if let Err(err) = append_block(&chain_id, &BlockData::AddProvider(fields)) {
+        return error_response(err);
+    }
+
+    // Unless told otherwise, the new provider can read every record we can
+    if parameters.get("share_records").and_then(Value::as_bool).unwrap_or(true) {
+        if let Err(err) = share_readable_records(&chain_id, &public_key) {
+            return error_response(err);
+        }
+    }
 
-    let hash = hash_block(&remove_provider_block);
-    remove_provider_block.hash = hash;
+    // The network task wraps the chain's keys to the provider's public key before sending them
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "add-provider".to_string(), parameters}).unwrap()).await;
 
-    let _ = insert_block(&remove_provider_block);
+    BlockchainResponse{ok: true, data: Value::Null}
+}
+
+pub async fn remove_provider(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    // The provider can be named by node id or by current address; the block always records the node id
+    let provider = match string_parameter(&parameters, "node_id").or_else(|_| string_parameter(&parameters, "ip")) {
+        Ok(provider) => provider,
+        Err(_) => return error_response("Missing or invalid parameter: node_id".to_string())
+    };
+    let (node_id, ip) = match find_provider(&chain_id, &provider) {
+        Ok(found) => found,
+        Err(err) => return error_response(err)
+    };
+    // Providers added before node ids can only be removed by address
+    let data = if node_id.is_empty() {
+        BlockData::RemoveProvider(RemoveProviderFields{ node_id: String::new(), ip: ip.clone() })
+    } else {
+        BlockData::RemoveProvider(RemoveProviderFields{ node_id: node_id.clone(), ip: String::new() })
+    };
+    if let Err(err) = append_block(&chain_id, &data) {
+        return error_response(err);
+    }
+
+    // The network thread tells the removed provider at its last known address
+    parameters.insert("node_id".to_string(), to_value(node_id).unwrap());
+    parameters.insert("ip".to_string(), to_value(ip).unwrap());
 
     let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "remove-provider".to_string(), parameters}).unwrap()).await;
 
-    // Fetch all blocks, and one-by-one, re-encrypt the data, check that the block hash is the same, and save to database
-    let new_key = generate_shared_key();
-    let _ = insert_new_shared_key(&new_key, chain_id.clone());
-    
-    let blocks = fetch_all_blocks(chain_id.clone()).unwrap();
-    for block in blocks {
-        let new_block_option = reencrypt_block(&block, shared_key, &new_key);
-        if let Some(new_block) = new_block_option {
-            let _ = update_block(&new_block);
-        }
+    // Start a new key epoch. Blocks already written stay under the keys of their own epochs, which the
+    // removed provider has seen anyway; only blocks from here on are hidden from it.
+    let epoch = get_current_epoch(chain_id.clone()).unwrap_or(0) + 1;
+    if let Err(err) = insert_shared_key(&generate_shared_key(), chain_id.clone(), epoch) {
+        return error_response(err.to_string());
     }
 
     let mut shared_key_params: Map<String, Value> = Map::default();
@@ -336,70 +658,490 @@ pub async fn remove_provider(parameters: Map<String, Value>, sender_to_p2p: &Sen
     }
 }
 
-pub fn create_chain(parameters: Map<String, Value>) -> BlockchainResponse {
-    // Generate a new symmetric key for encryption
-    let shared_key = generate_shared_key();
+// Look up a provider on a chain by node id or current address, returning its (node_id, ip_address).
+// Providers added before node ids have an empty node id.
+fn find_provider(chain_id: &str, provider: &str) -> Result<(String, String), String> {
+    let providers = fetch_projected_providers(chain_id.to_string()).unwrap_or_default();
+    // A provider added more than once has a row per add-provider block
+    let mut matches: Vec<(String, String)> = providers.into_iter()
+        .filter(|(_, ip, _, node_id)| node_id == provider || ip == provider)
+        .map(|(_, ip, _, node_id)| (node_id, ip))
+        .collect();
+    matches.sort();
+    matches.dedup();
+    match matches.len() {
+        0 => Err(format!("Unknown provider: {}", provider)),
+        1 => Ok(matches.remove(0)),
+        _ => Err(format!("More than one provider is at {}; name it by node id", provider))
+    }
+}
+
+pub async fn update_provider_address(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    // Without a node id, the address being updated is our own
+    if !parameters.contains_key("node_id") {
+        parameters.insert("node_id".to_string(), to_value(my_node_id()).unwrap());
+    }
+    let data = match fields_from_parameters(&parameters).map(BlockData::UpdateProviderAddress) {
+        Ok(data) => data,
+        Err(err) => return error_response(err)
+    };
+    if let Err(err) = append_block(&chain_id, &data) {
+        return error_response(err);
+    }
+
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "update-provider-address".to_string(), parameters}).unwrap()).await;
+
+    BlockchainResponse{ok: true, data: Value::Null}
+}
+
+// Record our current address on every chain where the address other providers have for us is out of date
+async fn update_own_addresses(sender_to_p2p: &Sender<String>) {
+    let my_ip = match local_ip() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => return
+    };
+    let my_node_id = my_node_id();
+    for chain_id in fetch_chain_ids().unwrap_or_default() {
+        if !is_chain_active(chain_id.clone()).unwrap_or(false) {
+            continue;
+        }
+        let providers = fetch_projected_providers(chain_id.clone()).unwrap_or_default();
+        if !providers.iter().any(|(_, ip, _, node_id)| *node_id == my_node_id && *ip != my_ip) {
+            continue;
+        }
+        let mut parameters: Map<String, Value> = Map::default();
+        parameters.insert("chain_id".to_string(), to_value(&chain_id).unwrap());
+        parameters.insert("ip".to_string(), to_value(&my_ip).unwrap());
+        let response = update_provider_address(parameters, sender_to_p2p).await;
+        if !response.ok {
+            eprintln!("Unable to update our address on chain {}: {}", chain_id, response.data);
+        }
+    }
+}
+
+pub async fn amend_record(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    let mut fields: AmendRecordFields = match fields_from_parameters(&parameters) {
+        Ok(fields) => fields,
+        Err(err) => return error_response(err)
+    };
+    if let Err(err) = validate_content(&fields.subject, &fields.details, &fields.attachments) {
+        return error_response(err);
+    }
+    if let Err(err) = check_record_reference(&chain_id, fields.block_id) {
+        return error_response(err);
+    }
+    if let Err(err) = fill_attachment_keys(&chain_id, &mut fields.attachments) {
+        return error_response(err);
+    }
+
+    // Records written before per-record keys are amended in the clear, like the record itself
+    let record_keys = fetch_record_keys(chain_id.clone(), fields.block_id).unwrap_or_default();
+    let data = if record_keys.is_empty() {
+        BlockData::AmendRecord(AmendRecordFields{ sealed: None, ..fields })
+    } else {
+        let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+        let data_key = match record_data_key(&record_keys, &my_key) {
+            Some(data_key) => data_key,
+            None => return error_response(format!("Record {} is restricted", fields.block_id))
+        };
+        let content = RecordContent{ subject: fields.subject, text: fields.text, details: fields.details, attachments: fields.attachments };
+        BlockData::AmendRecord(AmendRecordFields{
+            block_id: fields.block_id, subject: String::new(), text: String::new(), details: None, attachments: vec![],
+            sealed: Some(SealedRecord{ content: seal_record(&chain_id, &content, &data_key), keys: vec![], selected_readers: false })
+        })
+    };
+
+    if let Err(err) = append_block(&chain_id, &data) {
+        return error_response(err);
+    }
+    // New attachments only go to the providers that can read the record
+    if !record_keys.is_empty() {
+        let reader_ids: Vec<String> = record_keys.iter().map(|record_key| record_key.recipient.clone()).collect();
+        parameters.insert("readers".to_string(), to_value(reader_ids).unwrap());
+    }
+
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "amend-record".to_string(), parameters}).unwrap()).await;
+
+    BlockchainResponse{ok: true, data: Value::Null}
+}
+
+pub async fn retract_record(parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let chain_id = match string_parameter(&parameters, "chain_id") {
+        Ok(chain_id) => chain_id,
+        Err(err) => return error_response(err)
+    };
+    let fields: RetractRecordFields = match fields_from_parameters(&parameters) {
+        Ok(fields) => fields,
+        Err(err) => return error_response(err)
+    };
+    if let Err(err) = check_record_reference(&chain_id, fields.block_id) {
+        return error_response(err);
+    }
+
+    if let Err(err) = append_block(&chain_id, &BlockData::RetractRecord(fields)) {
+        return error_response(err);
+    }
+
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "retract-record".to_string(), parameters}).unwrap()).await;
+
+    BlockchainResponse{ok: true, data: Value::Null}
+}
+
+pub async fn share_record(mut parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let provider = string_parameter(&parameters, "node_id").or_else(|_| string_parameter(&parameters, "ip"));
+    let (chain_id, block_id, provider) = match (string_parameter(&parameters, "chain_id"), integer_parameter(&parameters, "block_id"), provider) {
+        (Ok(chain_id), Ok(block_id), Ok(provider)) => (chain_id, block_id, provider),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return error_response(err)
+    };
+    if let Err(err) = check_record_reference(&chain_id, block_id) {
+        return error_response(err);
+    }
+
+    let record_keys = fetch_record_keys(chain_id.clone(), block_id).unwrap_or_default();
+    if record_keys.is_empty() {
+        return error_response(format!("Record {} is already readable by every provider on the chain", block_id));
+    }
     let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    let data_key = match record_data_key(&record_keys, &my_key) {
+        Some(data_key) => data_key,
+        None => return error_response(format!("Record {} is restricted", block_id))
+    };
+    let provider_keys = fetch_provider_public_keys(chain_id.clone()).unwrap_or_default();
+    let recipients = match provider_public_keys(&provider_keys, &provider) {
+        Ok(recipients) => recipients,
+        Err(err) => return error_response(err)
+    };
 
-    let first_name = parameters.get("first_name").unwrap().as_str().unwrap().to_string();
-    let last_name = parameters.get("last_name").unwrap().as_str().unwrap().to_string();
-    let date_of_birth = parameters.get("date_of_birth").unwrap().as_str().unwrap().to_string();
+    let mut grants: Vec<RecordGrant> = vec![];
+    for public_key in recipients {
+        match wrap_key(&data_key, &public_key) {
+            Ok(key) => grants.push(RecordGrant{ block_id, recipient: key_id(&public_key), key }),
+            Err(err) => return error_response(err)
+        }
+    }
+    let reader_ids: Vec<String> = grants.iter().map(|grant| grant.recipient.clone()).collect();
+    if let Err(err) = append_block(&chain_id, &BlockData::ShareRecords(ShareRecordsFields{ grants })) {
+        return error_response(err);
+    }
 
-    // Generate global id for new chain
-    let id = Uuid::new_v4().to_string();
-    let data = BlockData{ action: "genesis".to_string(), fields: parameters };
-    let encrypted_data = encrypt_data(&data, &shared_key);
-    let hashed_data = hash_data(&data);
-
-    let mut genesis_block = Block{ 
-        chain_id: id.clone(), 
-        id: 0, 
-        timestamp: Utc::now().timestamp(), 
-        data: encrypted_data,
-        previous_hash: 0.to_string(), 
-        hash: "".to_string(), 
-        provider_key: my_key.public_key.clone(),
-        data_hash: hashed_data
+    // The providers the record is shared with also need every attachment it has had
+    parameters.insert("attachments".to_string(), to_value(record_attachments(&chain_id, block_id)).unwrap());
+    parameters.insert("readers".to_string(), to_value(reader_ids).unwrap());
+
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "share-records".to_string(), parameters}).unwrap()).await;
+
+    BlockchainResponse{ok: true, data: Value::Null}
+}
+
+// Wrap the data key of every sealed record we can read to a newly added provider, in a single block. Records whose
+// author picked the readers are left out, since the new provider wasn't one of them.
+fn share_readable_records(chain_id: &str, public_key: &str) -> Result<(), String> {
+    let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    let recipient = key_id(public_key);
+    let records = fetch_projected_records(chain_id.to_string()).unwrap_or_default();
+
+    let mut grants: Vec<RecordGrant> = vec![];
+    for record in records.into_iter().filter(|record| !record.selected_readers) {
+        let record_keys = fetch_record_keys(chain_id.to_string(), record.record_id).unwrap_or_default();
+        if record_keys.iter().any(|record_key| record_key.recipient == recipient) {
+            continue;
+        }
+        if let Some(data_key) = record_data_key(&record_keys, &my_key) {
+            grants.push(RecordGrant{ block_id: record.record_id, recipient: recipient.clone(), key: wrap_key(&data_key, public_key)? });
+        }
+    }
+    if grants.is_empty() {
+        return Ok(());
+    }
+    append_block(chain_id, &BlockData::ShareRecords(ShareRecordsFields{ grants })).map(|_| ())
+}
+
+// Every attachment a record or its amendments referenced
+fn record_attachments(chain_id: &str, record_id: i64) -> Vec<AttachmentReference> {
+    fetch_record_revisions(chain_id.to_string(), record_id).unwrap_or_default().into_iter()
+        .flat_map(|revision| revision.attachments)
+        .collect()
+}
+
+// Attachments a provider must not be sent: those only referenced by sealed records it holds no data key for.
+// It couldn't open them without the keys in those records, and refuses them.
+pub fn withheld_attachments(chain_id: &str, node_id: &str) -> HashSet<String> {
+    let mut withheld: HashSet<String> = HashSet::new();
+    let mut readable: HashSet<String> = HashSet::new();
+    for record in fetch_projected_records(chain_id.to_string()).unwrap_or_default() {
+        let record_keys = fetch_record_keys(chain_id.to_string(), record.record_id).unwrap_or_default();
+        let can_read = record_keys.is_empty() || record_keys.iter().any(|record_key| record_key.recipient == node_id);
+        let hashes = record_attachments(chain_id, record.record_id).into_iter().map(|attachment| attachment.hash);
+        if can_read {
+            readable.extend(hashes);
+        } else {
+            withheld.extend(hashes);
+        }
+    }
+    withheld.retain(|hash| !readable.contains(hash));
+    withheld
+}
+
+// Attachments are uploaded before the record that references them is created. Each reference carries the key
+// the file is encrypted with, so the record's readers, and only they, can open it. Files stored before
+// attachments had their own keys are encrypted with the chain key, and are referenced without one.
+fn fill_attachment_keys(chain_id: &str, attachments: &mut [AttachmentReference]) -> Result<(), String> {
+    for attachment in attachments.iter_mut() {
+        if !attachment_exists(chain_id, &attachment.hash) {
+            return Err(format!("Unknown attachment: {}", attachment.hash));
+        }
+        attachment.key = attachment_own_keys(chain_id, &attachment.hash).first().map(|key| key.to_hex()).unwrap_or_default();
+    }
+    Ok(())
+}
+
+// The keys of an attachment that we uploaded or that a record we can read gives
+fn attachment_own_keys(chain_id: &str, hash: &str) -> Vec<Vec<u8>> {
+    let mut keys: Vec<Vec<u8>> = fetch_attachment_key(chain_id, hash).ok().flatten().into_iter().collect();
+    for attachment in readable_attachments(chain_id).into_iter().filter(|attachment| attachment.hash == hash) {
+        if let Ok(key) = attachment.key.from_hex() {
+            if !key.is_empty() && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+// Every key an attachment may be encrypted with. The chain's keys are only tried for files that a record we
+// can read references without a key.
+pub fn attachment_keys(chain_id: &str, hash: &str) -> Vec<Vec<u8>> {
+    let mut keys = attachment_own_keys(chain_id, hash);
+    if readable_attachments(chain_id).iter().any(|attachment| attachment.hash == hash && attachment.key.is_empty()) {
+        keys.extend(epoch_keys(chain_id).unwrap_or_default());
+    }
+    keys
+}
+
+// Attachments referenced by the records we can read. The projection leaves those of other sealed records out.
+fn readable_attachments(chain_id: &str) -> Vec<AttachmentReference> {
+    fetch_projected_records(chain_id.to_string()).unwrap_or_default().into_iter()
+        .flat_map(|record| record_attachments(chain_id, record.record_id))
+        .collect()
+}
+
+pub fn upload_attachment(parameters: Map<String, Value>) -> BlockchainResponse {
+    let upload_id = match string_parameter(&parameters, "upload_id") {
+        Ok(upload_id) => upload_id,
+        Err(err) => return error_response(err)
     };
+    let bytes = match parameters.get("data").and_then(Value::as_str).unwrap_or("").from_hex() {
+        Ok(bytes) => bytes,
+        Err(_) => return error_response("Attachment data must be hex encoded".to_string())
+    };
+    if let Err(err) = append_upload(&upload_id, &bytes) {
+        return error_response(err);
+    }
 
-    let hash = hash_block(&genesis_block);
-    genesis_block.hash = hash.clone();
+    let mut data: Map<String, Value> = Map::default();
+    data.insert("upload_id".to_string(), to_value(&upload_id).unwrap());
+
+    // The final chunk moves the upload into the chain's encrypted attachment store
+    if parameters.get("final").and_then(Value::as_bool).unwrap_or(false) {
+        let chain_id = match string_parameter(&parameters, "chain_id") {
+            Ok(chain_id) => chain_id,
+            Err(err) => return error_response(err)
+        };
+        if !chain_exists(chain_id.clone()).unwrap_or(false) {
+            return error_response(format!("Unknown chain: {}", chain_id));
+        }
+        let plaintext = match take_upload(&upload_id) {
+            Ok(plaintext) => plaintext,
+            Err(err) => return error_response(err)
+        };
+        // Every attachment gets its own key, which the records referencing it carry. A file uploaded again keeps
+        // its key, and one stored under the chain key before attachments had their own is left as it is.
+        let hash = hash_bytes(&plaintext);
+        let legacy = read_attachment(&chain_id, &hash, &epoch_keys(&chain_id).unwrap_or_default()).is_ok();
+        let key = attachment_own_keys(&chain_id, &hash).into_iter().next();
+        if key.is_some() || !legacy {
+            let key = key.unwrap_or_else(|| generate_shared_key().to_vec());
+            if let Err(err) = store_attachment(&chain_id, &plaintext, &key).and_then(|_| insert_attachment_key(&chain_id, &hash, &key).map_err(|err| err.to_string())) {
+                return error_response(err);
+            }
+        }
+        data.insert("hash".to_string(), to_value(hash).unwrap());
+        data.insert("size".to_string(), to_value(plaintext.len()).unwrap());
+    }
 
-    let my_local_ip = local_ip().unwrap();
-    let mut fields: Map<String, Value> = Map::default();
-    fields.insert("ip".to_string(), my_local_ip.to_string().into());
-    fields.insert("name".to_string(), "OWNER".into());
+    BlockchainResponse{ok: true, data: Value::Object(data)}
+}
 
-    let data = BlockData{ action: "add-provider".to_string(), fields: fields };
-    let encrypted_data = encrypt_data(&data, &shared_key);
-    let hashed_data = hash_data(&data);
+// Sign this node's directory entry and send it to our peers, who pass it on in place of any older version
+pub async fn publish_directory_entry(parameters: Map<String, Value>, sender_to_p2p: &Sender<String>) -> BlockchainResponse {
+    let name = match string_parameter(&parameters, "name") {
+        Ok(name) => name,
+        Err(err) => return error_response(err)
+    };
+    let organization = parameters.get("organization").and_then(Value::as_str).unwrap_or_default().to_string();
+    let specialty = parameters.get("specialty").and_then(Value::as_str).unwrap_or_default().to_string();
+    // Unless given, the entry lists the address we can currently be reached at
+    let addresses: Vec<String> = match parameters.get("addresses").map(|addresses| from_value(addresses.clone())) {
+        Some(Ok(addresses)) => addresses,
+        Some(Err(_)) => return error_response("Missing or invalid parameter: addresses".to_string()),
+        None => local_ip().map(|ip| vec![ip.to_string()]).unwrap_or_default()
+    };
+    let key_pair = get_key_pair().unwrap().expect("Expected KeyPair");
+    let entry = match sign_entry(name, organization, specialty, addresses, &key_pair).and_then(store_entry) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return error_response("A newer directory entry is already published".to_string()),
+        Err(err) => return error_response(err)
+    };
 
-    let mut authorize_self_block = Block{
-        chain_id: id.clone(),
-        id: 1,
-        timestamp: Utc::now().timestamp(),
-        data: encrypted_data,
-        previous_hash: hash.clone(),
-        hash: "".to_string(),
-        provider_key: my_key.public_key,
-        data_hash: hashed_data,
+    let mut p2p_parameters: Map<String, Value> = Map::default();
+    p2p_parameters.insert("entries".to_string(), to_value(vec![&entry]).unwrap());
+    let _ = sender_to_p2p.send(to_string(&P2PRequest{action: "share-directory-entries".to_string(), parameters: p2p_parameters}).unwrap()).await;
+
+    BlockchainResponse{ok: true, data: to_value(entry).unwrap()}
+}
+
+pub fn search_directory(parameters: Map<String, Value>) -> BlockchainResponse {
+    let query = parameters.get("query").and_then(Value::as_str).unwrap_or_default();
+    match search_entries(query, parameters.get("specialty").and_then(Value::as_str)) {
+        Ok(entries) => BlockchainResponse{ok: true, data: to_value(entries).unwrap()},
+        Err(err) => error_response(err)
+    }
+}
+
+pub fn get_attachment(parameters: Map<String, Value>) -> BlockchainResponse {
+    let (chain_id, hash) = match (string_parameter(&parameters, "chain_id"), string_parameter(&parameters, "hash")) {
+        (Ok(chain_id), Ok(hash)) => (chain_id, hash),
+        (Err(err), _) | (_, Err(err)) => return error_response(err)
+    };
+    if !chain_exists(chain_id.clone()).unwrap_or(false) {
+        return error_response(format!("Unknown chain: {}", chain_id));
+    }
+    let plaintext = match read_attachment(&chain_id, &hash, &attachment_keys(&chain_id, &hash)) {
+        Ok(plaintext) => plaintext,
+        Err(err) => return error_response(err)
+    };
+
+    // Clients read large attachments a window at a time by advancing offset until done is true
+    let offset = (parameters.get("offset").and_then(Value::as_u64).unwrap_or(0) as usize).min(plaintext.len());
+    let length = parameters.get("length").and_then(Value::as_u64).map(|length| length as usize).unwrap_or(SOCKET_ATTACHMENT_WINDOW);
+    let end = offset.saturating_add(length).min(plaintext.len());
+
+    let mut data: Map<String, Value> = Map::default();
+    data.insert("hash".to_string(), to_value(hash).unwrap());
+    data.insert("offset".to_string(), to_value(offset).unwrap());
+    data.insert("size".to_string(), to_value(plaintext.len()).unwrap());
+    data.insert("data".to_string(), to_value(plaintext[offset..end].to_hex()).unwrap());
+    data.insert("done".to_string(), to_value(end == plaintext.len()).unwrap());
+
+    BlockchainResponse{ok: true, data: Value::Object(data)}
+}
+
+// Amendments and retractions must point at an add-record block that has not already been retracted
+fn check_record_reference(chain_id: &str, record_id: i64) -> Result<(), String> {
+    let revisions = record_revisions(chain_id, record_id)?;
+    match record_status(&revisions) {
+        None => Err(format!("Block {} is not a record", record_id)),
+        Some("retracted") => Err(format!("Record {} has been retracted", record_id)),
+        Some(_) => Ok(())
+    }
+}
+
+// Every block touching a record, in chain order, starting with its add-record block
+fn record_revisions(chain_id: &str, record_id: i64) -> Result<Vec<RecordRevision>, String> {
+    match fetch_record_revisions(chain_id.to_string(), record_id) {
+        Ok(revisions) => Ok(revisions),
+        Err(_) => Err(format!("Unknown chain: {}", chain_id))
+    }
+}
+
+// The record a block touches and the revision it makes to it, or None for blocks that aren't about records
+pub fn record_revision(block: &Block, data: &BlockData) -> Option<(i64, RecordRevision)> {
+    let (block_id, timestamp) = (block.id, block.timestamp);
+    match data.clone() {
+        BlockData::AddRecord(fields) => Some((block_id, content_revision(block_id, timestamp, "add-record", is_unopened(&fields.sealed, &fields.subject), RecordContent{
+            subject: fields.subject, text: fields.text, details: fields.details, attachments: fields.attachments
+        }))),
+        BlockData::AmendRecord(fields) => Some((fields.block_id, content_revision(block_id, timestamp, "amend-record", is_unopened(&fields.sealed, &fields.subject), RecordContent{
+            subject: fields.subject, text: fields.text, details: fields.details, attachments: fields.attachments
+        }))),
+        BlockData::RetractRecord(fields) => Some((fields.block_id, RecordRevision{
+            block_id, timestamp, action: "retract-record".to_string(), subject: None, text: None, details: None, attachments: vec![], reason: Some(fields.reason)
+        })),
+        _ => None
+    }
+}
+
+// Revisions of records we can't open are kept without their content
+fn content_revision(block_id: i64, timestamp: i64, action: &str, unopened: bool, content: RecordContent) -> RecordRevision {
+    if unopened {
+        return RecordRevision{ block_id, timestamp, action: action.to_string(), subject: None, text: None, details: None, attachments: vec![], reason: None };
+    }
+    RecordRevision{
+        block_id, timestamp, action: action.to_string(), subject: Some(content.subject), text: Some(content.text), details: content.details, attachments: content.attachments, reason: None
+    }
+}
+
+pub fn record_kind(details: &Option<RecordDetails>) -> &'static str {
+    match details {
+        Some(details) => details.kind(),
+        None => "note"
+    }
+}
+
+fn record_status(revisions: &[RecordRevision]) -> Option<&'static str> {
+    if revisions.is_empty() {
+        None
+    } else if revisions.iter().any(|revision| revision.action == "retract-record") {
+        Some("retracted")
+    } else if revisions.len() > 1 {
+        Some("amended")
+    } else {
+        Some("original")
+    }
+}
+
+pub fn create_chain(parameters: Map<String, Value>) -> BlockchainResponse {
+    // Generate a new symmetric key for encryption
+    let shared_key = generate_shared_key();
+    let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+
+    let genesis_fields: GenesisFields = match fields_from_parameters(&parameters) {
+        Ok(fields) => fields,
+        Err(err) => return error_response(err)
     };
+    let genesis_data = BlockData::Genesis(genesis_fields.clone());
+    if let Err(err) = genesis_data.validate() {
+        return error_response(err);
+    }
 
-    let hash = hash_block(&authorize_self_block);
-    authorize_self_block.hash = hash.clone();
+    // Generate global id for new chain
+    let id = Uuid::new_v4().to_string();
+    let genesis_block = build_block(id.clone(), 0, 0.to_string(), &genesis_data, &shared_key, 0, &my_key);
 
-    let new_chain = Chain { first_name: first_name, last_name: last_name, date_of_birth: date_of_birth, id: id.clone() };
+    let my_local_ip = local_ip().unwrap();
+    let owner_data = BlockData::AddProvider(AddProviderFields{ name: "OWNER".to_string(), ip: my_local_ip.to_string(), role: ProviderRole::Owner, public_key: my_key.public_key.clone() });
+    let authorize_self_block = build_block(id.clone(), 1, genesis_block.hash.clone(), &owner_data, &shared_key, 0, &my_key);
+
+    let new_chain = Chain { first_name: genesis_fields.first_name, last_name: genesis_fields.last_name, date_of_birth: genesis_fields.date_of_birth, id: id.clone(), integrity_failed: false };
     let _ = insert_chain(&new_chain);
-    let _ = insert_block(&genesis_block);
-    let _ = insert_block(&authorize_self_block);
-    let _ = insert_shared_key(&shared_key, id);
+    let _ = insert_block(&genesis_block, &genesis_data);
+    let _ = insert_block(&authorize_self_block, &owner_data);
+    let _ = insert_shared_key(&shared_key, id, 0);
     
     BlockchainResponse{ok: true, data: Value::Null}
 }
 
 pub fn get_last_block(chain_id: String) -> Block {
     match fetch_last_block(chain_id) {
-        Ok(block) => { return block },
+        Ok(block) => block,
         Err(_) => { panic!("Expected a block for this chain") }
     }
 }
@@ -429,73 +1171,454 @@ pub fn generate_key_pair() -> KeyPair {
     }
 }
 
-pub fn reencrypt_block(block: &Block, old_key: &[u8], new_key: &[u8]) -> Option<Block> {
-    let existing_hash = &block.hash;
-    let encrypted_data = &block.data;
-    let decrypted_data_result = decrypt_data(encrypted_data.as_str(), old_key);
-    match decrypted_data_result{
-        Ok(decrypted_data) => {
-            let reencrypted_data = encrypt_data(&decrypted_data, new_key);
-        
-            let new_block = Block { 
-                chain_id: block.chain_id.clone(), 
-                id: block.id.clone(), 
-                timestamp: block.timestamp.clone(), 
-                data: reencrypted_data, 
-                previous_hash: block.previous_hash.clone(), 
-                hash: block.hash.clone(), 
-                provider_key: block.provider_key.clone(), 
-                data_hash: block.data_hash.clone()
-            };
-        
-            let new_hash = hash_block(&new_block);
-            if new_hash != *existing_hash {
-                return None
+// Ingest a block received from a peer. Blocks we already have are ignored; blocks that fail validation are rejected,
+// and so are blocks that don't extend our copy, as diverged when the copies have split.
+// A block of an epoch whose key hasn't reached us yet is rejected as missing_key, so the sender tries again later.
+pub fn add_block(block: Block) -> Result<(), P2PError> {
+    let chain_id = block.chain_id.clone();
+    let block_id = block.id;
+
+    let block_data = open_received_block(&block)?;
+
+    // Chains we don't hold yet are taken in whole by add_new_chain
+    let lock = chain_lock(&chain_id);
+    let _guard = lock.lock().unwrap();
+    let last_block = match fetch_last_block(chain_id.clone()) {
+        Ok(last_block) => last_block,
+        Err(_) => return Err(p2p_error(P2PErrorCode::UnknownChain, format!("Chain {} is not held here", chain_id)))
+    };
+    // A block we already hold is ignored. Anything else must follow on from our last block, or the copies have split.
+    if block_id <= last_block.id {
+        return match fetch_block_hash(chain_id.clone(), block_id) {
+            Ok(hash) if hash == block.hash => Ok(()),
+            _ => Err(p2p_error(P2PErrorCode::Diverged, format!("Block {} differs from ours", block_id)))
+        };
+    }
+    if block_id > last_block.id + 1 {
+        return Err(invalid_block(format!("Blocks {} to {} are missing", last_block.id + 1, block_id - 1)));
+    }
+    if block.previous_hash != last_block.hash {
+        return Err(p2p_error(P2PErrorCode::Diverged, format!("Block {} doesn't follow on from our block {}", block_id, last_block.id)));
+    }
+    check_permission(&chain_id, &block.provider_key, &block_data).map_err(invalid_block)?;
+    match &block_data {
+        BlockData::AmendRecord(fields) => check_record_reference(&chain_id, fields.block_id).map_err(invalid_block)?,
+        BlockData::RetractRecord(fields) => check_record_reference(&chain_id, fields.block_id).map_err(invalid_block)?,
+        BlockData::ShareRecords(fields) => {
+            for grant in &fields.grants {
+                check_record_reference(&chain_id, grant.block_id).map_err(invalid_block)?;
             }
-        
-            Some(new_block)
         },
-        Err(_) => None
+        _ => {}
     }
+    insert_opened_block(&block, &block_data).map_err(|err| p2p_error(P2PErrorCode::Internal, err))?;
+    // A chain we were removed from is only active again once the block adding us back is stored. The removed node
+    // never gets the block removing it, so in its own copy it is still a provider, and any other block is stale.
+    if matches!(&block_data, BlockData::AddProvider(fields) if key_id(&fields.public_key) == my_node_id()) {
+        set_chain_active(chain_id, true).map_err(|err| p2p_error(P2PErrorCode::Internal, err.to_string()))?;
+    }
+    Ok(())
 }
 
-pub fn add_block(block: Block) {
-    let chain_id = block.chain_id.clone();
-    let block_id = block.id.clone();
+// Take in a chain we don't hold yet, given from its genesis block on. The chain's keys come from whichever nodes sent
+// them; nothing is stored until check_invitation, using one node's keys, finds the block that added us and shows that
+// node was an administrator or owner by then. Only that node's keys are kept. Returns whether this has happened.
+pub fn add_new_chain(blocks: &[Block]) -> Result<bool, P2PError> {
+    let genesis = &blocks[0];
+    let mut senders: HashMap<String, HashMap<i64, Vec<u8>>> = HashMap::new();
+    for (sender, epoch, key) in fetch_pending_keys(&genesis.chain_id).unwrap_or_default() {
+        senders.entry(sender).or_default().insert(epoch, key);
+    }
 
-    if chain_exists(chain_id.clone()).unwrap() {
-        let _ = set_chain_active(chain_id.clone(), true);
+    // Keys that don't open the chain, or that came from a node that can't share it, may sit alongside the right
+    // ones, which may also still be on their way. Until they arrive the sender is asked to try again later.
+    let my_node_id = my_node_id();
+    let mut waiting = false;
+    let mut last_error = format!("No keys for chain {} have been received", genesis.chain_id);
+    let mut verified: Option<HashMap<i64, Vec<u8>>> = None;
+    for (sender, keys) in senders {
+        match check_invitation(blocks, &my_node_id, &keys) {
+            Ok(Some(providers)) if highest_role(&providers, |id, _| id == sender).is_some_and(|role| role >= ProviderRole::Administrator) => {
+                verified = Some(keys);
+                break;
+            },
+            Ok(Some(_)) => last_error = format!("The keys for chain {} came from {}, which can't share them", genesis.chain_id, sender),
+            Ok(None) => waiting = true,
+            Err(err) => last_error = err.message
+        }
     }
+    let keys = match verified {
+        Some(keys) => keys,
+        None if waiting => return Ok(false),
+        None => return Err(p2p_error(P2PErrorCode::MissingKey, last_error))
+    };
 
-    let last_block_res = fetch_last_block(chain_id.clone());
+    let genesis_data = open_with_keys(genesis, &keys)?;
+    let fields = match &genesis_data {
+        BlockData::Genesis(fields) => fields.clone(),
+        _ => return Err(invalid_block("First block of a chain must be a genesis block".to_string()))
+    };
+    {
+        // Another peer may have sent us the same chain meanwhile, in which case the blocks are added to its copy
+        let lock = chain_lock(&genesis.chain_id);
+        let _guard = lock.lock().unwrap();
+        if !chain_exists(genesis.chain_id.clone()).unwrap_or(false) {
+            for (epoch, key) in &keys {
+                insert_shared_key(key, genesis.chain_id.clone(), *epoch).map_err(|err| p2p_error(P2PErrorCode::Internal, format!("Unable to save key: {}", err)))?;
+            }
+            let new_chain = Chain{ id: genesis.chain_id.clone(), first_name: fields.first_name, last_name: fields.last_name, date_of_birth: fields.date_of_birth, integrity_failed: false };
+            insert_chain(&new_chain).map_err(|err| p2p_error(P2PErrorCode::Internal, format!("Unable to save chain: {}", err)))?;
+            insert_block(genesis, &genesis_data).map_err(|err| p2p_error(P2PErrorCode::Internal, format!("Unable to save block: {}", err)))?;
+        }
+        let _ = delete_pending_keys(&genesis.chain_id);
+    }
+    for block in &blocks[1..] {
+        add_block(block.clone())?;
+    }
+    Ok(true)
+}
+
+// Blocks are added from the socket, from inbound connections and from catch-up at the same time. Writers to a chain
+// take turns, so two of them can't both build on the same last block.
+static CHAIN_LOCKS: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn chain_lock(chain_id: &str) -> Arc<Mutex<()>> {
+    CHAIN_LOCKS.lock().unwrap().entry(chain_id.to_string()).or_default().clone()
+}
+
+fn invalid_block(message: String) -> P2PError {
+    p2p_error(P2PErrorCode::InvalidBlock, message)
+}
+
+// Check a received block's header and signature, then decrypt and validate its payload
+fn open_received_block(block: &Block) -> Result<BlockData, P2PError> {
+    check_received_header(block)?;
+    let shared_key = block_key(block).map_err(|err| p2p_error(P2PErrorCode::MissingKey, err))?;
+    validate_block_data(block, &shared_key).map_err(invalid_block)
+}
+
+// The same for a block of a chain we don't hold yet, with keys by epoch that aren't stored
+fn open_with_keys(block: &Block, keys: &HashMap<i64, Vec<u8>>) -> Result<BlockData, P2PError> {
+    check_received_header(block)?;
+    match keys.get(&block.key_epoch) {
+        Some(shared_key) => validate_block_data(block, shared_key).map_err(invalid_block),
+        None => Err(p2p_error(P2PErrorCode::MissingKey, format!("No key for epoch {} of chain {}", block.key_epoch, block.chain_id)))
+    }
+}
+
+// Only accept blocks whose header is intact and signed by the provider that claims to have written them. Earlier
+// versions hash the header ambiguously or leave the key epoch out of it, so they are only verified in chains already
+// stored, never taken in.
+fn check_received_header(block: &Block) -> Result<(), P2PError> {
+    if block.version != CURRENT_BLOCK_VERSION {
+        return Err(invalid_block(format!("Unsupported block version {}", block.version)));
+    }
+    if hash_block(block) != block.hash || !verify_block_signature(block) {
+        return Err(invalid_block("Invalid hash or signature".to_string()));
+    }
+    Ok(())
+}
 
-    match last_block_res {
-        Ok(last_block) => {
-            if block_id == last_block.id + 1 {
-                let _ = insert_block(&block);
+// Node id, address and role of each provider on a chain
+type Providers = Vec<(String, String, ProviderRole)>;
+
+// A chain we don't hold yet is only taken in once its blocks show we were added to it. From the genesis block on,
+// each block must follow on from the one before and be signed by a provider its role allows to write it, up to an
+// add-provider block naming us. Blocks are opened with the given keys by epoch. Once that block has arrived, returns
+// the node id, address and role of each provider as of it.
+pub fn check_invitation(blocks: &[Block], node_id: &str, keys: &HashMap<i64, Vec<u8>>) -> Result<Option<Providers>, P2PError> {
+    // Node id, address and role of each provider as of the block being checked
+    let mut providers: Providers = vec![];
+    let mut previous: Option<&Block> = None;
+    for block in blocks {
+        let follows = match previous {
+            Some(previous) => block.id == previous.id + 1 && block.previous_hash == previous.hash,
+            None => block.id == 0
+        };
+        if !follows {
+            return Err(invalid_block(format!("Block {} of new chain {} doesn't follow on from the one before", block.id, block.chain_id)));
+        }
+        let data = open_with_keys(block, keys)?;
+        let signer = key_id(&block.provider_key);
+        if let BlockData::Genesis(_) = data {
+            if block.id != 0 {
+                return Err(invalid_block("Only the first block of a chain can be a genesis block".to_string()));
             }
+            providers.push((signer, String::new(), ProviderRole::Owner));
+            previous = Some(block);
+            continue;
+        }
+
+        // The same rules check_permission applies to blocks of a chain we hold
+        let role = match highest_role(&providers, |id, _| id == signer) {
+            Some(role) => role,
+            None => return Err(invalid_block(format!("Block {} of new chain {} is signed by a node that isn't a provider", block.id, block.chain_id)))
+        };
+        let target_role = match &data {
+            BlockData::AddProvider(fields) => Some(fields.role),
+            BlockData::RemoveProvider(fields) if fields.node_id.is_empty() => highest_role(&providers, |_, ip| ip == fields.ip),
+            BlockData::RemoveProvider(fields) => highest_role(&providers, |id, _| id == fields.node_id),
+            BlockData::UpdateProviderAddress(fields) => highest_role(&providers, |id, _| id == fields.node_id),
+            _ => None
+        };
+        // Any provider may record its own new address
+        let own_address = matches!(&data, BlockData::UpdateProviderAddress(fields) if fields.node_id == signer);
+        if !own_address && !role.can_append(&data, target_role.unwrap_or_default()) {
+            return Err(invalid_block(format!("Block {} of new chain {}: {} cannot {}", block.id, block.chain_id, role.as_str(), data.action())));
+        }
+
+        match data {
+            BlockData::AddProvider(fields) if !fields.public_key.is_empty() => {
+                let added = key_id(&fields.public_key);
+                if added == node_id {
+                    return Ok(Some(providers));
+                }
+                providers.push((added, fields.ip, fields.role));
+            },
+            BlockData::RemoveProvider(fields) if fields.node_id.is_empty() => providers.retain(|(_, ip, _)| *ip != fields.ip),
+            BlockData::RemoveProvider(fields) => providers.retain(|(id, _, _)| *id != fields.node_id),
+            BlockData::UpdateProviderAddress(fields) => {
+                for provider in providers.iter_mut().filter(|(id, _, _)| *id == fields.node_id) {
+                    provider.1 = fields.ip.clone();
+                }
+            },
+            _ => {}
+        }
+        previous = Some(block);
+    }
+    Ok(None)
+}
+
+// The highest role held by the providers that match, given as node id, address and role
+fn highest_role(providers: &[(String, String, ProviderRole)], matches: impl Fn(&str, &str) -> bool) -> Option<ProviderRole> {
+    providers.iter().filter(|(id, ip, _)| matches(id, ip)).map(|(_, _, role)| *role).max()
+}
+
+// Decrypt an inbound block, check it against its data hash and validate the typed payload
+fn validate_block_data(block: &Block, shared_key: &[u8]) -> Result<BlockData, String> {
+    if !block.data.starts_with(GCM_DATA_PREFIX) {
+        return Err("Block data is not encrypted with AES-256-GCM".to_string());
+    }
+    let plaintext = match decrypt_plaintext(&block.data, shared_key, &block.chain_id, block.id) {
+        Ok(plaintext) => plaintext,
+        Err(_) => return Err("Unable to decrypt block data".to_string())
+    };
+    if hash_bytes(&plaintext) != block.data_hash {
+        return Err("Data hash does not match block data".to_string());
+    }
+    let block_data: BlockData = match serde_json::from_slice(&plaintext) {
+        Ok(block_data) => block_data,
+        Err(err) => return Err(format!("Malformed block data: {}", err))
+    };
+    block_data.validate()?;
+    Ok(block_data)
+}
+
+// Validate a payload and append it to a local chain as a new block signed by us
+fn append_block(chain_id: &str, data: &BlockData) -> Result<Block, String> {
+    data.validate()?;
+
+    let lock = chain_lock(chain_id);
+    let _guard = lock.lock().unwrap();
+
+    let (shared_key, key_epoch) = match (get_shared_key(chain_id.to_string()), get_current_epoch(chain_id.to_string())) {
+        (Ok(key), Ok(epoch)) => (key, epoch),
+        _ => return Err(format!("Unknown chain: {}", chain_id))
+    };
+    let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    check_permission(chain_id, &my_key.public_key, data)?;
+    let last_block = match fetch_last_block(chain_id.to_string()) {
+        Ok(block) => block,
+        Err(_) => return Err(format!("Unknown chain: {}", chain_id))
+    };
+
+    let block = build_block(chain_id.to_string(), last_block.id + 1, last_block.hash, data, &shared_key, key_epoch, &my_key);
+    insert_opened_block(&block, data)?;
+    Ok(block)
+}
+
+// Insert a block, projecting the content of any sealed record we hold the data key for
+fn insert_opened_block(block: &Block, data: &BlockData) -> Result<(), String> {
+    let chain_id = block.chain_id.as_str();
+    let my_key = get_key_pair().unwrap().expect("Expected KeyPair");
+    let opened = open_block_data(block, data.clone(), &my_key, &|record_id| fetch_record_keys(chain_id.to_string(), record_id).unwrap_or_default());
+    if let Err(err) = insert_block(block, &opened) {
+        return Err(format!("Unable to save block: {}", err));
+    }
+
+    // Records shared with us after they were written can only be opened by re-projecting the chain
+    let my_id = key_id(&my_key.public_key);
+    if let BlockData::ShareRecords(fields) = data {
+        if fields.grants.iter().any(|grant| grant.recipient == my_id) {
+            rebuild_projection(chain_id)?;
+        }
+    }
+    Ok(())
+}
+
+// Fill in the content of sealed records we hold a data key for. record_keys returns the keys
+// wrapped for a record, including those granted after it was written, which amendments are sealed with too.
+fn open_block_data(block: &Block, data: BlockData, key_pair: &KeyPair, record_keys: &dyn Fn(i64) -> Vec<RecordKey>) -> BlockData {
+    let chain_id = block.chain_id.as_str();
+    match data {
+        BlockData::AddRecord(mut fields) => {
+            let content = fields.sealed.as_ref().and_then(|sealed| {
+                let keys: Vec<RecordKey> = sealed.keys.iter().cloned().chain(record_keys(block.id)).collect();
+                record_data_key(&keys, key_pair).and_then(|data_key| open_record(chain_id, sealed, &data_key))
+            });
+            if let Some(content) = content {
+                fields.subject = content.subject;
+                fields.text = content.text;
+                fields.details = content.details;
+                fields.attachments = content.attachments;
+            }
+            BlockData::AddRecord(fields)
         },
-        Err(_) => {
-            if block_id == 0 {
-                let shared_key = get_shared_key(chain_id.clone()).unwrap();
-                let decrypted_data = decrypt_data(&block.data.clone(), &shared_key).unwrap();
-                let first_name = decrypted_data.fields.get("first_name").unwrap().as_str().unwrap().to_string();
-                let last_name = decrypted_data.fields.get("last_name").unwrap().as_str().unwrap().to_string();
-                let date_of_birth = decrypted_data.fields.get("date_of_birth").unwrap().as_str().unwrap().to_string();
-                let id = chain_id.clone();
-                let new_chain = Chain{ id: id, first_name: first_name, last_name: last_name, date_of_birth: date_of_birth };
-                let _ = insert_chain(&new_chain);
-                let _ = insert_block(&block);
+        BlockData::AmendRecord(mut fields) => {
+            let content = fields.sealed.as_ref().and_then(|sealed| {
+                record_data_key(&record_keys(fields.block_id), key_pair).and_then(|data_key| open_record(chain_id, sealed, &data_key))
+            });
+            if let Some(content) = content {
+                fields.subject = content.subject;
+                fields.text = content.text;
+                fields.details = content.details;
+                fields.attachments = content.attachments;
             }
-        }
+            BlockData::AmendRecord(fields)
+        },
+        data => data
     }
 }
 
+// Our copy of a record's data key, if it has been wrapped to us
+fn record_data_key(record_keys: &[RecordKey], key_pair: &KeyPair) -> Option<Vec<u8>> {
+    let my_id = key_id(&key_pair.public_key);
+    record_keys.iter()
+        .find(|record_key| record_key.recipient == my_id)
+        .and_then(|record_key| unwrap_key(&record_key.key, &key_pair.private_key))
+}
+
+// Record content is bound to its chain, and each record's data key is used for it and its amendments only
+fn seal_record(chain_id: &str, content: &RecordContent, data_key: &[u8]) -> String {
+    seal(to_string(content).unwrap().as_bytes(), data_key, chain_id.as_bytes()).to_hex()
+}
+
+fn open_record(chain_id: &str, sealed: &SealedRecord, data_key: &[u8]) -> Option<RecordContent> {
+    let bytes = sealed.content.from_hex().ok()?;
+    let plaintext = open(&bytes, data_key, chain_id.as_bytes()).ok()?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+// Providers are named by the SHA-256 of their DER SubjectPublicKeyInfo, so a key gives the same id however its PEM
+// is wrapped or encoded, and the same id as the key in its certificate. Text that isn't a public key can't match
+// any certificate, so it is hashed as it is.
+pub fn key_id(public_key: &str) -> String {
+    let pem = public_key.as_bytes();
+    let pkey = PKey::public_key_from_pem(pem).ok()
+        .or_else(|| Rsa::public_key_from_pem_pkcs1(pem).ok().and_then(|rsa| PKey::from_rsa(rsa).ok()));
+    match pkey.and_then(|pkey| pkey.public_key_to_der().ok()) {
+        Some(der) => der_key_id(&der),
+        None => hash_bytes(pem)
+    }
+}
+
+pub fn der_key_id(der: &[u8]) -> String {
+    hash_bytes(der)
+}
+
+// Wrap a symmetric key with RSA-OAEP so only the holder of the matching private key can recover it
+pub fn wrap_key(key: &[u8], public_key: &str) -> Result<String, String> {
+    let rsa = match Rsa::public_key_from_pem(public_key.as_bytes()) {
+        Ok(rsa) => rsa,
+        Err(_) => return Err("Invalid public key".to_string())
+    };
+    let mut wrapped = vec![0u8; rsa.size() as usize];
+    match rsa.public_encrypt(key, &mut wrapped, Padding::PKCS1_OAEP) {
+        Ok(length) => Ok(wrapped[..length].to_hex()),
+        Err(_) => Err("Unable to wrap key".to_string())
+    }
+}
+
+pub fn unwrap_key(wrapped: &str, private_key: &[u8]) -> Option<Vec<u8>> {
+    let wrapped = wrapped.from_hex().ok()?;
+    let rsa = PKey::private_key_from_pkcs8(private_key).ok()?.rsa().ok()?;
+    let mut key = vec![0u8; rsa.size() as usize];
+    let length = rsa.private_decrypt(&wrapped, &mut key, Padding::PKCS1_OAEP).ok()?;
+    key.truncate(length);
+    Some(key)
+}
+
+// Create the next block for a chain: encrypt the payload, hash the header and sign the hash with our private key
+fn build_block(chain_id: String, id: i64, previous_hash: String, data: &BlockData, shared_key: &[u8], key_epoch: i64, key_pair: &KeyPair) -> Block {
+    let encrypted_data = encrypt_data(data, shared_key, &chain_id, id);
+    let mut block = Block{
+        chain_id,
+        id,
+        timestamp: Utc::now().timestamp(),
+        data: encrypted_data,
+        previous_hash,
+        hash: "".to_string(),
+        provider_key: key_pair.public_key.clone(),
+        data_hash: hash_data(data),
+        signature: "".to_string(),
+        version: CURRENT_BLOCK_VERSION,
+        key_epoch
+    };
+
+    block.hash = hash_block(&block);
+    block.signature = sign_block(&block, &key_pair.private_key);
+    block
+}
+
+fn sign_block(block: &Block, private_key: &[u8]) -> String {
+    sign_bytes(block.hash.as_bytes(), private_key)
+}
+
+pub fn verify_block_signature(block: &Block) -> bool {
+    verify_signature(block.hash.as_bytes(), &block.signature, &block.provider_key)
+}
+
+// RSA signature over SHA-256, hex encoded
+pub fn sign_bytes(bytes: &[u8], private_key: &[u8]) -> String {
+    let pkey = PKey::private_key_from_pkcs8(private_key).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(bytes).unwrap();
+    signer.sign_to_vec().unwrap().to_hex()
+}
+
+pub fn verify_signature(bytes: &[u8], signature: &str, public_key: &str) -> bool {
+    let signature = match signature.from_hex() {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    let pkey = match PKey::public_key_from_pem(public_key.as_bytes()) {
+        Ok(pkey) => pkey,
+        Err(_) => return false
+    };
+    let mut verifier = match Verifier::new(MessageDigest::sha256(), &pkey) {
+        Ok(verifier) => verifier,
+        Err(_) => return false
+    };
+    verifier.update(bytes).is_ok() && verifier.verify(&signature).unwrap_or(false)
+}
 
 fn hash_block(block: &Block) -> String {
-    let mut block_clone = block.clone();
-    block_clone.hash = "".to_string();
-    let serialized = format!(
+    let serialized = match block.version {
+        0 => legacy_block_header(block),
+        _ => canonical_block_header(block)
+    };
+
+    // Compute the SHA-256 hash
+    let mut hasher = Sha256::new();
+    hasher.update(&serialized);
+    let result = hasher.finish();
+
+    result.to_hex()
+}
+
+// Pre-versioning header, kept so chains written before version 1 still verify
+fn legacy_block_header(block: &Block) -> Vec<u8> {
+    format!(
         "{}{}{}{}{}{}",
         block.chain_id,
         block.id,
@@ -503,38 +1626,356 @@ fn hash_block(block: &Block) -> String {
         block.previous_hash,
         block.provider_key,
         block.data_hash
-    );
+    ).into_bytes()
+}
 
-    // Compute the SHA-256 hash
-    let mut hasher = Sha256::new();
-    hasher.update(serialized.as_bytes());
-    let result = hasher.finish();
+// Each field is written with an 8-byte big-endian length prefix so no two headers share a preimage
+fn canonical_block_header(block: &Block) -> Vec<u8> {
+    let mut header: Vec<u8> = vec![];
+    let fields: [&[u8]; 7] = [
+        &block.version.to_be_bytes(),
+        block.chain_id.as_bytes(),
+        &block.id.to_be_bytes(),
+        &block.timestamp.to_be_bytes(),
+        block.previous_hash.as_bytes(),
+        block.provider_key.as_bytes(),
+        block.data_hash.as_bytes()
+    ];
+    for field in fields {
+        header.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        header.extend_from_slice(field);
+    }
+    if block.version >= 2 {
+        let key_epoch = block.key_epoch.to_be_bytes();
+        header.extend_from_slice(&(key_epoch.len() as u64).to_be_bytes());
+        header.extend_from_slice(&key_epoch);
+    }
+    header
+}
 
-    result.to_hex()
+// The shared key a block was encrypted with, looked up by the epoch recorded in its header
+pub fn block_key(block: &Block) -> Result<Vec<u8>, String> {
+    match get_epoch_key(block.chain_id.clone(), block.key_epoch) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(format!("No key for epoch {} of chain {}", block.key_epoch, block.chain_id))
+    }
+}
+
+// Every key a chain has used, newest first, for attachments stored before they had their own keys
+pub fn epoch_keys(chain_id: &str) -> Result<Vec<Vec<u8>>, String> {
+    match fetch_epoch_keys(chain_id.to_string()) {
+        Ok(keys) if !keys.is_empty() => Ok(keys.into_iter().map(|(_, key)| key).collect()),
+        _ => Err(format!("Unknown chain: {}", chain_id))
+    }
 }
 
 fn hash_data(data: &BlockData) -> String {
+    hash_bytes(to_string(data).unwrap().as_bytes())
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
     let mut sha256 = Sha256::new();
-    sha256.update(to_string(data).unwrap().as_bytes());
+    sha256.update(bytes);
     let result = sha256.finish();
     result.to_hex()
 }
 
-fn encrypt_data(data: &BlockData, key: &[u8]) -> String {
-    let cipher = Cipher::aes_256_cbc();
-    let iv = [0; 16];
-    let ciphertext = encrypt(cipher, key, Some(&iv), to_string(data).unwrap().as_bytes()).unwrap();
-    let hex_encoded_ciphertext = ciphertext.to_hex();
-    hex_encoded_ciphertext
+fn encrypt_data(data: &BlockData, key: &[u8], chain_id: &str, block_id: i64) -> String {
+    encrypt_plaintext(to_string(data).unwrap().as_bytes(), key, chain_id, block_id)
+}
+
+fn decrypt_data(encrypted_data: &str, key: &[u8], chain_id: &str, block_id: i64) -> Result<BlockData, String> {
+    let decrypted_data = match decrypt_stored_plaintext(encrypted_data, key, chain_id, block_id) {
+        Ok(decrypted_data) => decrypted_data,
+        Err(_) => return Err(format!("Unable to decrypt block {}", block_id))
+    };
+
+    // Parse the JSON into a typed BlockData payload
+    match serde_json::from_slice(&decrypted_data) {
+        Ok(block_data) => Ok(block_data),
+        Err(err) => Err(format!("Malformed data in block {}: {}", block_id, err))
+    }
 }
 
-fn decrypt_data(encrypted_data: &str, key: &[u8]) -> Result<BlockData, ErrorStack> {
+// Block data is stored as "v2:" followed by hex(nonce || ciphertext || tag), encrypted with AES-256-GCM.
+// The chain id and block id are bound in as associated data so ciphertext can't be moved between blocks.
+fn encrypt_plaintext(plaintext: &[u8], key: &[u8], chain_id: &str, block_id: i64) -> String {
+    let aad = block_associated_data(chain_id, block_id);
+    format!("{}{}", GCM_DATA_PREFIX, seal(plaintext, key, &aad).to_hex())
+}
+
+// Decrypt block data to the exact bytes that were hashed into data_hash
+fn decrypt_plaintext(encrypted_data: &str, key: &[u8], chain_id: &str, block_id: i64) -> Result<Vec<u8>, ErrorStack> {
+    let bytes = match encrypted_data.strip_prefix(GCM_DATA_PREFIX).map(|encoded| encoded.from_hex()) {
+        Some(Ok(bytes)) => bytes,
+        _ => return Err(ErrorStack::get())
+    };
+    let aad = block_associated_data(chain_id, block_id);
+    open(&bytes, key, &aad)
+}
+
+// The same for a block we already hold. Data without a version prefix was stored before AES-256-GCM, in the legacy
+// hex-encoded AES-256-CBC format with a zero IV; blocks from peers are never accepted in it.
+fn decrypt_stored_plaintext(encrypted_data: &str, key: &[u8], chain_id: &str, block_id: i64) -> Result<Vec<u8>, ErrorStack> {
+    if encrypted_data.starts_with(GCM_DATA_PREFIX) {
+        return decrypt_plaintext(encrypted_data, key, chain_id, block_id);
+    }
+
     let cipher = Cipher::aes_256_cbc();
     let iv = [0; 16];
-    let ciphertext = encrypted_data.from_hex().unwrap();
-    let decrypted_data = decrypt(cipher, key, Some(&iv), &ciphertext)?;
-    let decrypted_string = String::from_utf8(decrypted_data).expect("UTF-8 decoding error");
+    let ciphertext = match encrypted_data.from_hex() {
+        Ok(ciphertext) => ciphertext,
+        Err(_) => return Err(ErrorStack::get())
+    };
+    decrypt(cipher, key, Some(&iv), &ciphertext)
+}
+
+// AES-256-GCM with a random nonce, encoded as nonce || ciphertext || tag
+pub fn seal(plaintext: &[u8], key: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; GCM_NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let mut tag = [0u8; GCM_TAG_LENGTH];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), aad, plaintext, &mut tag).unwrap();
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+    sealed
+}
+
+pub fn open(sealed: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    if sealed.len() < GCM_NONCE_LENGTH + GCM_TAG_LENGTH {
+        return Err(ErrorStack::get());
+    }
+    let (nonce, rest) = sealed.split_at(GCM_NONCE_LENGTH);
+    let (ciphertext, tag) = rest.split_at(rest.len() - GCM_TAG_LENGTH);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, ciphertext, tag)
+}
+
+fn block_associated_data(chain_id: &str, block_id: i64) -> Vec<u8> {
+    format!("{}:{}", chain_id, block_id).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::symm::encrypt;
+
+    const CHAIN_ID: &str = "chain";
+
+    fn genesis() -> BlockData {
+        BlockData::Genesis(GenesisFields{ first_name: "Ann".to_string(), last_name: "Lee".to_string(), date_of_birth: "1990-01-01".to_string() })
+    }
+
+    fn add_provider(name: &str, ip: &str, role: ProviderRole, key_pair: &KeyPair) -> BlockData {
+        BlockData::AddProvider(AddProviderFields{ name: name.to_string(), ip: ip.to_string(), role, public_key: key_pair.public_key.clone() })
+    }
+
+    // Each payload becomes the next block, signed by the key pair it is paired with
+    fn build_chain(payloads: Vec<(BlockData, &KeyPair)>, shared_key: &[u8]) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for (id, (data, key_pair)) in payloads.into_iter().enumerate() {
+            let previous_hash = blocks.last().map(|block| block.hash.clone()).unwrap_or_else(|| 0.to_string());
+            blocks.push(build_block(CHAIN_ID.to_string(), id as i64, previous_hash, &data, shared_key, 0, key_pair));
+        }
+        blocks
+    }
+
+    fn header_block(chain_id: &str, id: i64, version: i64) -> Block {
+        Block{
+            chain_id: chain_id.to_string(), id, timestamp: 1_700_000_000, data: String::new(), previous_hash: "0".to_string(), hash: String::new(),
+            provider_key: "key".to_string(), data_hash: "data".to_string(), signature: String::new(), version, key_epoch: 0
+        }
+    }
+
+    #[test]
+    fn canonical_header_keeps_fields_apart_where_legacy_header_does_not() {
+        let first = header_block("chain1", 23, 2);
+        let second = header_block("chain12", 3, 2);
+        assert_eq!(legacy_block_header(&first), legacy_block_header(&second));
+        assert_ne!(canonical_block_header(&first), canonical_block_header(&second));
+    }
 
-    // Parse the JSON string into BlockData struct
-    Ok(from_str(&decrypted_string).expect("JSON deserialization error"))
-}
\ No newline at end of file
+    #[test]
+    fn key_epoch_is_only_hashed_from_version_2() {
+        let mut block = header_block(CHAIN_ID, 1, 2);
+        let header = canonical_block_header(&block);
+        block.key_epoch = 1;
+        assert_ne!(canonical_block_header(&block), header);
+
+        let mut block = header_block(CHAIN_ID, 1, 1);
+        let header = canonical_block_header(&block);
+        block.key_epoch = 1;
+        assert_eq!(canonical_block_header(&block), header);
+    }
+
+    #[test]
+    fn hash_format_follows_block_version() {
+        let block = header_block(CHAIN_ID, 1, 0);
+        assert_eq!(hash_block(&block), hash_bytes(&legacy_block_header(&block)));
+        let block = header_block(CHAIN_ID, 1, 2);
+        assert_eq!(hash_block(&block), hash_bytes(&canonical_block_header(&block)));
+    }
+
+    #[test]
+    fn received_header_needs_intact_hash_and_signature() {
+        let key_pair = generate_key_pair();
+        let block = build_block(CHAIN_ID.to_string(), 0, 0.to_string(), &genesis(), &generate_shared_key(), 0, &key_pair);
+        assert!(verify_block_signature(&block));
+        assert!(check_received_header(&block).is_ok());
+
+        let mut tampered = block.clone();
+        tampered.timestamp += 1;
+        assert_eq!(check_received_header(&tampered).unwrap_err().code, P2PErrorCode::InvalidBlock);
+
+        let mut resigned = block.clone();
+        resigned.signature = sign_block(&block, &generate_key_pair().private_key);
+        assert!(!verify_block_signature(&resigned));
+        assert!(check_received_header(&resigned).is_err());
+    }
+
+    #[test]
+    fn received_blocks_must_be_the_current_version() {
+        let key_pair = generate_key_pair();
+        let mut block = build_block(CHAIN_ID.to_string(), 0, 0.to_string(), &genesis(), &generate_shared_key(), 0, &key_pair);
+        block.version = 1;
+        block.hash = hash_block(&block);
+        block.signature = sign_block(&block, &key_pair.private_key);
+        assert!(verify_block_signature(&block));
+        assert!(check_received_header(&block).is_err());
+    }
+
+    #[test]
+    fn signatures_only_verify_the_signed_bytes_and_key() {
+        let key_pair = generate_key_pair();
+        let signature = sign_bytes(b"signed", &key_pair.private_key);
+        assert!(verify_signature(b"signed", &signature, &key_pair.public_key));
+        assert!(!verify_signature(b"other", &signature, &key_pair.public_key));
+        assert!(!verify_signature(b"signed", &signature, &generate_key_pair().public_key));
+        assert!(!verify_signature(b"signed", "not hex", &key_pair.public_key));
+    }
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let key = generate_shared_key();
+        let sealed = seal(b"plaintext", &key, b"aad");
+        assert_eq!(open(&sealed, &key, b"aad").unwrap(), b"plaintext");
+        // Every seal uses a fresh nonce
+        assert_ne!(seal(b"plaintext", &key, b"aad"), sealed);
+    }
+
+    #[test]
+    fn open_refuses_wrong_key_associated_data_or_tampering() {
+        let key = generate_shared_key();
+        let sealed = seal(b"plaintext", &key, b"aad");
+        assert!(open(&sealed, &generate_shared_key(), b"aad").is_err());
+        assert!(open(&sealed, &key, b"other").is_err());
+        let mut tampered = sealed.clone();
+        tampered[GCM_NONCE_LENGTH] ^= 1;
+        assert!(open(&tampered, &key, b"aad").is_err());
+        assert!(open(&sealed[..GCM_NONCE_LENGTH + GCM_TAG_LENGTH - 1], &key, b"aad").is_err());
+    }
+
+    #[test]
+    fn block_data_is_bound_to_its_block() {
+        let key = generate_shared_key();
+        let encrypted = encrypt_plaintext(b"data", &key, CHAIN_ID, 1);
+        assert!(encrypted.starts_with(GCM_DATA_PREFIX));
+        assert_eq!(decrypt_plaintext(&encrypted, &key, CHAIN_ID, 1).unwrap(), b"data");
+        assert!(decrypt_plaintext(&encrypted, &key, CHAIN_ID, 2).is_err());
+        assert!(decrypt_plaintext(&encrypted, &key, "other", 1).is_err());
+    }
+
+    #[test]
+    fn legacy_cbc_data_only_opens_when_stored() {
+        let key = generate_shared_key();
+        let legacy = encrypt(Cipher::aes_256_cbc(), &key, Some(&[0; 16]), b"legacy").unwrap().to_hex();
+        assert_eq!(decrypt_stored_plaintext(&legacy, &key, CHAIN_ID, 1).unwrap(), b"legacy");
+        assert!(decrypt_plaintext(&legacy, &key, CHAIN_ID, 1).is_err());
+
+        let current = encrypt_plaintext(b"current", &key, CHAIN_ID, 1);
+        assert_eq!(decrypt_stored_plaintext(&current, &key, CHAIN_ID, 1).unwrap(), b"current");
+    }
+
+    #[test]
+    fn received_block_data_must_be_gcm() {
+        let key_pair = generate_key_pair();
+        let key = generate_shared_key();
+        let mut block = build_block(CHAIN_ID.to_string(), 0, 0.to_string(), &genesis(), &key, 0, &key_pair);
+        assert!(validate_block_data(&block, &key).is_ok());
+        block.data = encrypt(Cipher::aes_256_cbc(), &key, Some(&[0; 16]), to_string(&genesis()).unwrap().as_bytes()).unwrap().to_hex();
+        assert!(validate_block_data(&block, &key).is_err());
+    }
+
+    #[test]
+    fn invitation_returns_providers_once_we_are_added() {
+        let (owner, admin, us) = (generate_key_pair(), generate_key_pair(), generate_key_pair());
+        let key = generate_shared_key();
+        let blocks = build_chain(vec![
+            (genesis(), &owner),
+            (add_provider("Owner", "10.0.0.1", ProviderRole::Owner, &owner), &owner),
+            (add_provider("Admin", "10.0.0.2", ProviderRole::Administrator, &admin), &owner),
+            (add_provider("Us", "10.0.0.3", ProviderRole::Reader, &us), &admin),
+        ], &key);
+        let keys = HashMap::from([(0, key.to_vec())]);
+
+        let providers = check_invitation(&blocks, &key_id(&us.public_key), &keys).unwrap().unwrap();
+        assert!(providers.contains(&(key_id(&admin.public_key), "10.0.0.2".to_string(), ProviderRole::Administrator)));
+        // Until the block adding us arrives, the invitation is still open
+        assert!(check_invitation(&blocks[..3], &key_id(&us.public_key), &keys).unwrap().is_none());
+    }
+
+    #[test]
+    fn invitation_refuses_blocks_their_signer_may_not_write() {
+        let (owner, stranger, us) = (generate_key_pair(), generate_key_pair(), generate_key_pair());
+        let key = generate_shared_key();
+        let keys = HashMap::from([(0, key.to_vec())]);
+
+        let blocks = build_chain(vec![
+            (genesis(), &owner),
+            (add_provider("Owner", "10.0.0.1", ProviderRole::Owner, &owner), &owner),
+            (add_provider("Us", "10.0.0.3", ProviderRole::Reader, &us), &stranger),
+        ], &key);
+        assert!(check_invitation(&blocks, &key_id(&us.public_key), &keys).is_err());
+
+        let blocks = build_chain(vec![
+            (genesis(), &owner),
+            (add_provider("Owner", "10.0.0.1", ProviderRole::Owner, &owner), &owner),
+            (add_provider("Reader", "10.0.0.2", ProviderRole::Reader, &stranger), &owner),
+            (add_provider("Us", "10.0.0.3", ProviderRole::Reader, &us), &stranger),
+        ], &key);
+        assert!(check_invitation(&blocks, &key_id(&us.public_key), &keys).is_err());
+    }
+
+    #[test]
+    fn invitation_refuses_broken_links_and_missing_keys() {
+        let (owner, us) = (generate_key_pair(), generate_key_pair());
+        let key = generate_shared_key();
+        let mut blocks = build_chain(vec![
+            (genesis(), &owner),
+            (add_provider("Owner", "10.0.0.1", ProviderRole::Owner, &owner), &owner),
+            (add_provider("Us", "10.0.0.3", ProviderRole::Reader, &us), &owner),
+        ], &key);
+        let us = key_id(&us.public_key);
+
+        let missing = check_invitation(&blocks, &us, &HashMap::new()).unwrap_err();
+        assert_eq!(missing.code, P2PErrorCode::MissingKey);
+
+        let keys = HashMap::from([(0, key.to_vec())]);
+        blocks.remove(1);
+        assert!(check_invitation(&blocks, &us, &keys).is_err());
+    }
+
+    #[test]
+    fn key_id_ignores_how_the_key_is_encoded() {
+        let key_pair = generate_key_pair();
+        let rsa = Rsa::public_key_from_pem(key_pair.public_key.as_bytes()).unwrap();
+        let pkcs1 = String::from_utf8(rsa.public_key_to_pem_pkcs1().unwrap()).unwrap();
+        let crlf = key_pair.public_key.replace('\n', "\r\n");
+        let der = PKey::from_rsa(rsa).unwrap().public_key_to_der().unwrap();
+
+        assert_eq!(key_id(&key_pair.public_key), der_key_id(&der));
+        assert_eq!(key_id(&pkcs1), der_key_id(&der));
+        assert_eq!(key_id(&crlf), der_key_id(&der));
+    }
+}
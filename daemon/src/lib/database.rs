@@ -1,14 +1,56 @@
-use rusqlite::{params, Connection, Result};
-use crate::blockchain::{generate_key_pair, Block, Chain};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Serialize;
+use serde_json::{from_str, to_string};
+use crate::directory::DirectoryEntry;
+use crate::blockchain::{generate_key_pair, key_id, record_kind, record_revision, Block, Chain, RecordRevision};
+use crate::payload::{is_unopened, BlockData, RecordDetails, RecordKey};
 
 const DB_STRING: &str = "ehr.sqlite";
 
+// Kind given to records whose sealed content we can't open
+pub const RESTRICTED_KIND: &str = "restricted";
+
 #[derive(Debug)]
 pub struct KeyPair {
     pub public_key: String,
     pub private_key: Vec<u8>,
 }
 
+// Current state of a record in the patient-state projection
+#[derive(Debug)]
+pub struct ProjectedRecord {
+    pub record_id: i64,
+    pub timestamp: i64,
+    pub subject: String,
+    pub status: String,
+    pub kind: String,
+    pub details: Option<RecordDetails>,
+    pub selected_readers: bool,
+}
+
+// A message waiting to be delivered to a peer. The message is the P2P request to send, except for actions worked out
+// at delivery time: it is empty for syncing a chain and the hash for sending an attachment.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub node_id: String,
+    pub ip: String,
+    pub action: String,
+    pub chain_id: String,
+    #[serde(skip)]
+    pub dedup_key: String,
+    #[serde(skip)]
+    pub message: String,
+    pub attempts: i64,
+    pub created: i64,
+    pub next_attempt: i64,
+    pub last_error: String,
+    // The code of the last error, from the peer or from trying to reach it
+    pub error_code: String,
+    // Refused by the peer in a way retrying won't fix, so it is kept only to be reported
+    pub failed: bool,
+}
+
 // ----- Insertions and Updates ----- //
 
 pub fn insert_chain(chain: &Chain) -> Result<()> {
@@ -23,37 +65,79 @@ pub fn set_chain_active(chain_id: String, active: bool) -> Result<()>{
     Ok(())
 }
 
-pub fn insert_block(block: &Block) -> Result<()> {
+// Recorded by the integrity check at startup, and cleared once the chain passes it again
+pub fn set_chain_integrity_failed(chain_id: &str, failed: bool) -> Result<()> {
     let conn = Connection::open(DB_STRING)?;
-    conn.execute("INSERT INTO blocks (chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash) 
-                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);", 
-                        params![block.chain_id, block.id, block.timestamp, block.data, block.previous_hash, block.hash, block.provider_key, block.data_hash])?;
+    conn.execute("UPDATE chains SET integrity_failed = ? WHERE id = ?", params![failed, chain_id])?;
     Ok(())
 }
 
-pub fn update_block(block: &Block) -> Result<()> {
+pub fn is_chain_integrity_failed(chain_id: &str) -> Result<bool> {
     let conn = Connection::open(DB_STRING)?;
-    conn.execute("UPDATE blocks SET (data) = ? WHERE chain_id = ? and id = ?", 
-                        params![block.data, block.chain_id, block.id])?;
-    Ok(())
+    conn.query_row("SELECT integrity_failed FROM chains WHERE id = ?", params![chain_id], |row| row.get(0))
 }
 
-pub fn insert_shared_key(shared_key: &[u8], chain_id: String) -> Result<()> {
+// The block and its effect on the patient-state projection are written in one transaction
+pub fn insert_block(block: &Block, data: &BlockData) -> Result<()> {
+    let mut conn = Connection::open(DB_STRING)?;
+    let transaction = conn.transaction()?;
+    transaction.execute("INSERT INTO blocks (chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash, signature, version, key_epoch) 
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11);", 
+                        params![block.chain_id, block.id, block.timestamp, block.data, block.previous_hash, block.hash, block.provider_key, block.data_hash, block.signature, block.version, block.key_epoch])?;
+    apply_to_projection(&transaction, block, data)?;
+    transaction.commit()
+}
+
+// Store the key for one epoch of a chain. The newest epoch's key is the active one, used for new blocks.
+pub fn insert_shared_key(shared_key: &[u8], chain_id: String, epoch: i64) -> Result<()> {
     let conn = Connection::open(DB_STRING)?;
+    let existing: Option<Vec<u8>> = conn.query_row("SELECT value FROM shared_keys WHERE chain_id = ? AND epoch = ? ORDER BY id DESC LIMIT 1",
+        params![chain_id, epoch], |row| row.get(0)).optional()?;
+    if existing.as_deref() == Some(shared_key) {
+        return Ok(());
+    }
+
     conn.execute(
-        "INSERT INTO shared_keys (chain_id, value, active) VALUES (?, ?, ?)",
-        params![chain_id, &shared_key, true],
+        "INSERT INTO shared_keys (chain_id, value, active, epoch) VALUES (?, ?, ?, ?)",
+        params![chain_id, &shared_key, false, epoch],
     )?;
+    conn.execute("UPDATE shared_keys SET active = 0 WHERE chain_id = ?", params![chain_id])?;
+    conn.execute("UPDATE shared_keys SET active = 1 WHERE id = (SELECT id FROM shared_keys WHERE chain_id = ? ORDER BY epoch DESC, id DESC LIMIT 1)", params![chain_id])?;
     Ok(())
 }
 
-pub fn insert_new_shared_key(shared_key: &[u8], chain_id: String) -> Result<()> {
+// Keys a node sent for a chain we don't hold yet, kept apart from the chain's keys until the chain shows that node
+// could share them. A node sending the key for an epoch again replaces the one it sent before.
+pub fn insert_pending_key(chain_id: &str, sender: &str, epoch: i64, key: &[u8]) -> Result<()> {
     let conn = Connection::open(DB_STRING)?;
-    conn.execute("UPDATE shared_keys SET active = 0 WHERE chain_id = ?", params![chain_id])?;
-    conn.execute(
-        "INSERT INTO shared_keys (chain_id, value, active) VALUES (?, ?, ?)",
-        params![chain_id, &shared_key, true],
-    )?;
+    conn.execute("INSERT OR REPLACE INTO pending_keys (chain_id, sender, epoch, value) VALUES (?, ?, ?, ?)", params![chain_id, sender, epoch, key])?;
+    Ok(())
+}
+
+// Chains other than the given one a node has sent keys for that we don't hold yet
+pub fn count_pending_key_chains(sender: &str, except_chain_id: &str) -> Result<usize> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT COUNT(DISTINCT chain_id) FROM pending_keys WHERE sender = ? AND chain_id != ?", params![sender, except_chain_id], |row| row.get(0))
+}
+
+// Every key sent for a chain we don't hold yet, as (sender, epoch, key)
+pub fn fetch_pending_keys(chain_id: &str) -> Result<Vec<(String, i64, Vec<u8>)>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT sender, epoch, value FROM pending_keys WHERE chain_id = ?")?;
+    let keys = statement.query_map(params![chain_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    keys.collect()
+}
+
+pub fn delete_pending_keys(chain_id: &str) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute("DELETE FROM pending_keys WHERE chain_id = ?", params![chain_id])?;
+    Ok(())
+}
+
+// The key of an attachment we encrypted ourselves, kept until and after a record references it
+pub fn insert_attachment_key(chain_id: &str, hash: &str, key: &[u8]) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute("INSERT OR REPLACE INTO attachment_keys (chain_id, hash, value) VALUES (?, ?, ?)", params![chain_id, hash, key])?;
     Ok(())
 }
 
@@ -69,14 +153,15 @@ fn insert_key_pair(conn: &Connection, key_pair: KeyPair) -> Result<()>{
 
 pub fn fetch_chains() -> Result<Vec<Chain>, rusqlite::Error> {
     let conn = Connection::open(DB_STRING)?;
-    let query = "SELECT id, first_name, last_name, date_of_birth FROM chains WHERE active = 1";
+    let query = "SELECT id, first_name, last_name, date_of_birth, integrity_failed FROM chains WHERE active = 1";
     let mut stmt = conn.prepare(query)?;
     let chain_iter = stmt.query_map([], |row| {
         Ok(Chain {
             id: row.get(0)?,
             first_name: row.get(1)?,
             last_name: row.get(2)?,
-            date_of_birth: row.get(2)?
+            date_of_birth: row.get(2)?,
+            integrity_failed: row.get(4)?
         })
     })?;
 
@@ -84,32 +169,28 @@ pub fn fetch_chains() -> Result<Vec<Chain>, rusqlite::Error> {
     chains
 }
 
-pub fn fetch_all_transactions(id: String) -> Result<Vec<(i64, i64, String)>> {
+pub fn fetch_chain_ids() -> Result<Vec<String>> {
     let conn = Connection::open(DB_STRING)?;
+    let mut stmt = conn.prepare("SELECT id FROM chains")?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    ids.collect()
+}
 
-    let mut statement = conn.prepare("SELECT timestamp, id, data FROM blocks WHERE chain_id = ? ORDER BY timestamp ASC").unwrap();
-    let blocks = statement.query_map(params![id], |row| {
-        Ok((
-            row.get::<usize, i64>(0)?,
-            row.get::<usize, i64>(1)?,
-            row.get::<usize, String>(2)?,
-        ))
-    })?;
-
-    let mut result = Vec::new();
-
-    for block in blocks {
-        result.push(block?);
-    }
-
-    Ok(result)
+pub fn fetch_date_of_birth(chain_id: String) -> Result<String> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT date_of_birth FROM chains WHERE id = ?", params![chain_id], |row| row.get(0))
 }
 
 pub fn fetch_all_blocks(id: String) -> Result<Vec<Block>> {
+    fetch_blocks_from(id, 0)
+}
+
+// The blocks of a chain from the given id onwards, for peers that already hold the ones before it
+pub fn fetch_blocks_from(id: String, from_id: i64) -> Result<Vec<Block>> {
     let conn = Connection::open(DB_STRING)?;
 
-    let mut statement = conn.prepare("SELECT chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash FROM blocks WHERE chain_id = ? ORDER BY timestamp ASC").unwrap();
-    let block_tuples = statement.query_map(params![id], |row| {
+    let mut statement = conn.prepare("SELECT chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash, signature, version, key_epoch FROM blocks WHERE chain_id = ? AND id >= ? ORDER BY id ASC").unwrap();
+    let block_tuples = statement.query_map(params![id, from_id], |row| {
         Ok((
             row.get::<usize, String>(0)?,
             row.get::<usize, i64>(1)?,
@@ -119,6 +200,9 @@ pub fn fetch_all_blocks(id: String) -> Result<Vec<Block>> {
             row.get::<usize, String>(5)?,
             row.get::<usize, String>(6)?,
             row.get::<usize, String>(7)?,
+            row.get::<usize, String>(8)?,
+            row.get::<usize, i64>(9)?,
+            row.get::<usize, i64>(10)?,
         ))
     })?;
 
@@ -134,7 +218,10 @@ pub fn fetch_all_blocks(id: String) -> Result<Vec<Block>> {
             previous_hash: block_tuple.4,
             hash: block_tuple.5, 
             provider_key: block_tuple.6,
-            data_hash: block_tuple.7
+            data_hash: block_tuple.7,
+            signature: block_tuple.8,
+            version: block_tuple.9,
+            key_epoch: block_tuple.10
         };
         result.push(block);
     }
@@ -158,6 +245,11 @@ pub fn fetch_record(chain_id: String, block_id: i64) -> Result<(i64, String)> {
     Ok(record)
 }
 
+pub fn fetch_block_hash(chain_id: String, block_id: i64) -> Result<String> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT hash FROM blocks WHERE chain_id = ? AND id = ?", params![chain_id, block_id], |row| row.get(0))
+}
+
 pub fn chain_exists(id: String) -> Result<bool>{
     let conn = Connection::open(DB_STRING)?;
     let query = "SELECT EXISTS(SELECT 1 FROM chains WHERE id = ?)";
@@ -168,7 +260,7 @@ pub fn chain_exists(id: String) -> Result<bool>{
 pub fn is_chain_active(id: String) -> Result<bool> {
     let conn = Connection::open(DB_STRING)?;
     let query = "SELECT active from chains where id = ?";
-    let mut statement = conn.prepare(&query)?;
+    let mut statement = conn.prepare(query)?;
     let result = statement.query_row([id], |row| {
         let value: i32 = row.get(0)?;
         Ok(value != 0)
@@ -179,11 +271,9 @@ pub fn is_chain_active(id: String) -> Result<bool> {
 pub fn fetch_last_block(chain_id: String) -> Result<Block> {
     let conn = Connection::open(DB_STRING)?;
 
-    let query = format!(
-        "SELECT chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash FROM blocks WHERE chain_id = ? AND id = (SELECT MAX(id) FROM blocks WHERE chain_id = ?)"
-    );
+    let query = "SELECT chain_id, id, timestamp, data, previous_hash, hash, provider_key, data_hash, signature, version, key_epoch FROM blocks WHERE chain_id = ? AND id = (SELECT MAX(id) FROM blocks WHERE chain_id = ?)";
 
-    let mut statement = conn.prepare(&query)?;
+    let mut statement = conn.prepare(query)?;
     let mut rows = statement.query(params![chain_id, chain_id])?;
 
     if let Some(row) = rows.next()? {
@@ -196,12 +286,16 @@ pub fn fetch_last_block(chain_id: String) -> Result<Block> {
             hash: row.get(5)?,
             provider_key: row.get(6)?,
             data_hash: row.get(7)?,
+            signature: row.get(8)?,
+            version: row.get(9)?,
+            key_epoch: row.get(10)?,
         })
     } else {
-        Err(rusqlite::Error::QueryReturnedNoRows.into())
+        Err(rusqlite::Error::QueryReturnedNoRows)
     }
 }
 
+// The key of the chain's current epoch
 pub fn get_shared_key(id: String) -> Result<Vec<u8>> {
     let conn = Connection::open(DB_STRING)?;
 
@@ -216,6 +310,30 @@ pub fn get_shared_key(id: String) -> Result<Vec<u8>> {
     }
 }
 
+pub fn fetch_attachment_key(chain_id: &str, hash: &str) -> Result<Option<Vec<u8>>> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT value FROM attachment_keys WHERE chain_id = ? AND hash = ?", params![chain_id, hash], |row| row.get(0)).optional()
+}
+
+pub fn get_current_epoch(chain_id: String) -> Result<i64> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT epoch FROM shared_keys WHERE chain_id = ? AND active = 1", params![chain_id], |row| row.get(0))
+}
+
+// Keys that were replaced before epochs existed share epoch 0; the newest of them is the one blocks were last encrypted with
+pub fn get_epoch_key(chain_id: String, epoch: i64) -> Result<Vec<u8>> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT value FROM shared_keys WHERE chain_id = ? AND epoch = ? ORDER BY id DESC LIMIT 1", params![chain_id, epoch], |row| row.get(0))
+}
+
+// Every epoch's key for a chain as (epoch, key), newest epoch first
+pub fn fetch_epoch_keys(chain_id: String) -> Result<Vec<(i64, Vec<u8>)>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT epoch, value FROM shared_keys WHERE id IN (SELECT MAX(id) FROM shared_keys WHERE chain_id = ? GROUP BY epoch) ORDER BY epoch DESC")?;
+    let keys = statement.query_map(params![chain_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    keys.collect()
+}
+
 pub fn get_key_pair() -> Result<Option<KeyPair>>{
     let conn = Connection::open(DB_STRING)?;
     let mut stmt = conn.prepare("SELECT public_key, private_key FROM user_key_pairs LIMIT 1")?;
@@ -240,6 +358,303 @@ pub fn get_key_pair() -> Result<Option<KeyPair>>{
     }
 }
 
+// ----- Patient state projection ----- //
+
+// Providers as (name, ip_address, role, node_id), in the order they were added. Providers added before
+// public keys were recorded have an empty node id.
+pub fn fetch_projected_providers(chain_id: String) -> Result<Vec<(String, String, String, String)>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT name, ip, role, node_id FROM providers WHERE chain_id = ? ORDER BY block_id ASC")?;
+    let providers = statement.query_map(params![chain_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+    providers.collect()
+}
+
+// Roles granted to the provider with this public key on a chain, one per add-provider block
+pub fn fetch_provider_roles_by_key(chain_id: String, public_key: String) -> Result<Vec<String>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT role FROM providers WHERE chain_id = ? AND public_key = ?")?;
+    let roles = statement.query_map(params![chain_id, public_key], |row| row.get(0))?;
+    roles.collect()
+}
+
+pub fn fetch_provider_roles_by_node_id(chain_id: String, node_id: String) -> Result<Vec<String>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT role FROM providers WHERE chain_id = ? AND node_id = ?")?;
+    let roles = statement.query_map(params![chain_id, node_id], |row| row.get(0))?;
+    roles.collect()
+}
+
+// Only needed for remove-provider blocks written before node ids, which name the provider by ip
+pub fn fetch_provider_roles_by_ip(chain_id: String, ip: String) -> Result<Vec<String>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT role FROM providers WHERE chain_id = ? AND ip = ?")?;
+    let roles = statement.query_map(params![chain_id, ip], |row| row.get(0))?;
+    roles.collect()
+}
+
+// Public keys of the providers on a chain as (ip_address, public_key), for those whose key is known
+pub fn fetch_provider_public_keys(chain_id: String) -> Result<Vec<(String, String)>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT ip, public_key FROM providers WHERE chain_id = ? AND public_key != '' ORDER BY block_id ASC")?;
+    let keys = statement.query_map(params![chain_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    keys.collect()
+}
+
+// A record's data key as wrapped for each provider that has been given it
+pub fn fetch_record_keys(chain_id: String, record_id: i64) -> Result<Vec<RecordKey>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT recipient, key FROM record_keys WHERE chain_id = ? AND record_id = ?")?;
+    let keys = statement.query_map(params![chain_id, record_id], |row| Ok(RecordKey{ recipient: row.get(0)?, key: row.get(1)? }))?;
+    keys.collect()
+}
+
+// The key that signed a chain's genesis block, which belongs to the chain's creator
+pub fn fetch_genesis_key(chain_id: String) -> Result<String> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT provider_key FROM blocks WHERE chain_id = ? AND id = 0", params![chain_id], |row| row.get(0))
+}
+
+pub fn fetch_projected_records(chain_id: String) -> Result<Vec<ProjectedRecord>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT record_id, timestamp, subject, status, kind, details, selected_readers FROM records WHERE chain_id = ? ORDER BY record_id ASC")?;
+    let records = statement.query_map(params![chain_id], |row| {
+        let details: Option<String> = row.get(5)?;
+        Ok(ProjectedRecord{
+            record_id: row.get(0)?,
+            timestamp: row.get(1)?,
+            subject: row.get(2)?,
+            status: row.get(3)?,
+            kind: row.get(4)?,
+            details: details.and_then(|details| from_str(&details).ok()),
+            selected_readers: row.get(6)?
+        })
+    })?;
+    records.collect()
+}
+
+// Every add, amend and retract block for a record, in chain order
+pub fn fetch_record_revisions(chain_id: String, record_id: i64) -> Result<Vec<RecordRevision>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT revision FROM record_revisions WHERE chain_id = ? AND record_id = ? ORDER BY block_id ASC")?;
+    let revisions = statement.query_map(params![chain_id, record_id], |row| row.get::<usize, String>(0))?;
+
+    let mut result = Vec::new();
+    for revision in revisions {
+        if let Ok(revision) = from_str(&revision?) {
+            result.push(revision);
+        }
+    }
+    Ok(result)
+}
+
+// Chains whose projection is behind their blocks, such as those stored before the projection existed
+pub fn fetch_stale_projection_chain_ids() -> Result<Vec<String>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT id FROM chains WHERE projected_height != (SELECT COALESCE(MAX(id), -1) FROM blocks WHERE blocks.chain_id = chains.id)")?;
+    let ids = statement.query_map([], |row| row.get(0))?;
+    ids.collect()
+}
+
+// Replace a chain's projection with one built from its decrypted blocks, given in chain order
+pub fn replace_projection(chain_id: String, blocks: &[(Block, BlockData)]) -> Result<()> {
+    let mut conn = Connection::open(DB_STRING)?;
+    let transaction = conn.transaction()?;
+    transaction.execute("DELETE FROM providers WHERE chain_id = ?", params![chain_id])?;
+    transaction.execute("DELETE FROM records WHERE chain_id = ?", params![chain_id])?;
+    transaction.execute("DELETE FROM record_revisions WHERE chain_id = ?", params![chain_id])?;
+    transaction.execute("DELETE FROM record_keys WHERE chain_id = ?", params![chain_id])?;
+    transaction.execute("UPDATE chains SET projected_height = -1 WHERE id = ?", params![chain_id])?;
+    for (block, data) in blocks {
+        apply_to_projection(&transaction, block, data)?;
+    }
+    transaction.commit()
+}
+
+fn apply_to_projection(conn: &Connection, block: &Block, data: &BlockData) -> Result<()> {
+    match data {
+        BlockData::Genesis(_) => {},
+        BlockData::AddProvider(fields) => {
+            let node_id = if fields.public_key.is_empty() { String::new() } else { key_id(&fields.public_key) };
+            conn.execute("INSERT INTO providers (chain_id, block_id, name, ip, role, public_key, node_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![block.chain_id, block.id, fields.name, fields.ip, fields.role.as_str(), fields.public_key, node_id])?;
+        },
+        BlockData::RemoveProvider(fields) => {
+            if fields.node_id.is_empty() {
+                conn.execute("DELETE FROM providers WHERE chain_id = ? AND ip = ?", params![block.chain_id, fields.ip])?;
+            } else {
+                conn.execute("DELETE FROM providers WHERE chain_id = ? AND node_id = ?", params![block.chain_id, fields.node_id])?;
+            }
+        },
+        BlockData::UpdateProviderAddress(fields) => {
+            conn.execute("UPDATE providers SET ip = ? WHERE chain_id = ? AND node_id = ?", params![fields.ip, block.chain_id, fields.node_id])?;
+        },
+        BlockData::AddRecord(fields) => {
+            // Records we hold no data key for are kept so later blocks can refer to them, but only as restricted
+            let kind = if is_unopened(&fields.sealed, &fields.subject) { RESTRICTED_KIND } else { record_kind(&fields.details) };
+            let selected_readers = fields.sealed.as_ref().map(|sealed| sealed.selected_readers).unwrap_or(false);
+            conn.execute("INSERT INTO records (chain_id, record_id, timestamp, subject, status, kind, details, selected_readers) VALUES (?, ?, ?, ?, 'original', ?, ?, ?)",
+                params![block.chain_id, block.id, block.timestamp, fields.subject, kind, details_json(&fields.details), selected_readers])?;
+            if let Some(sealed) = &fields.sealed {
+                for record_key in &sealed.keys {
+                    insert_record_key(conn, &block.chain_id, block.id, &record_key.recipient, &record_key.key)?;
+                }
+            }
+        },
+        BlockData::AmendRecord(fields) if is_unopened(&fields.sealed, &fields.subject) => {
+            conn.execute("UPDATE records SET status = 'amended' WHERE chain_id = ? AND record_id = ? AND status != 'retracted'", params![block.chain_id, fields.block_id])?;
+        },
+        BlockData::AmendRecord(fields) => {
+            conn.execute("UPDATE records SET subject = ?, status = 'amended', kind = ?, details = ? WHERE chain_id = ? AND record_id = ? AND status != 'retracted'",
+                params![fields.subject, record_kind(&fields.details), details_json(&fields.details), block.chain_id, fields.block_id])?;
+        },
+        BlockData::ShareRecords(fields) => {
+            for grant in &fields.grants {
+                insert_record_key(conn, &block.chain_id, grant.block_id, &grant.recipient, &grant.key)?;
+            }
+        },
+        BlockData::RetractRecord(fields) => {
+            conn.execute("UPDATE records SET status = 'retracted' WHERE chain_id = ? AND record_id = ?", params![block.chain_id, fields.block_id])?;
+        },
+    }
+
+    // Revisions are only kept for blocks that refer to a record that exists
+    if let Some((record_id, revision)) = record_revision(block, data) {
+        let record_exists = conn.query_row("SELECT 1 FROM records WHERE chain_id = ? AND record_id = ?", params![block.chain_id, record_id], |_| Ok(())).optional()?.is_some();
+        if record_exists {
+            conn.execute("INSERT INTO record_revisions (chain_id, record_id, block_id, revision) VALUES (?, ?, ?, ?)",
+                params![block.chain_id, record_id, block.id, to_string(&revision).unwrap()])?;
+        }
+    }
+
+    conn.execute("UPDATE chains SET projected_height = ? WHERE id = ?", params![block.id, block.chain_id])?;
+    Ok(())
+}
+
+fn insert_record_key(conn: &Connection, chain_id: &str, record_id: i64, recipient: &str, key: &str) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO record_keys (chain_id, record_id, recipient, key) VALUES (?, ?, ?, ?)", params![chain_id, record_id, recipient, key])?;
+    Ok(())
+}
+
+fn details_json(details: &Option<RecordDetails>) -> Option<String> {
+    details.as_ref().map(|details| to_string(details).unwrap())
+}
+
+// ----- Outbox ----- //
+
+// Queue a message for a peer. A message with the same dedup key already waiting for that peer is replaced, so
+// repeated changes don't pile up. The new one goes to the back of the queue, behind anything queued since, and keeps
+// the old one's retry schedule, unless the old one had failed for good.
+pub fn queue_outbox(node_id: &str, ip: &str, action: &str, chain_id: &str, dedup_key: &str, message: &str, now: i64) -> Result<()> {
+    let mut conn = Connection::open(DB_STRING)?;
+    let transaction = conn.transaction()?;
+    let existing: Option<(i64, i64, i64)> = transaction.query_row("SELECT attempts, created, next_attempt FROM outbox WHERE node_id = ? AND dedup_key = ? AND failed = 0",
+        params![node_id, dedup_key], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).optional()?;
+    let (attempts, created, next_attempt) = existing.unwrap_or((0, now, now));
+    transaction.execute("DELETE FROM outbox WHERE node_id = ? AND dedup_key = ?", params![node_id, dedup_key])?;
+    transaction.execute(
+        "INSERT INTO outbox (node_id, ip, action, chain_id, dedup_key, message, attempts, created, next_attempt, last_error)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, '')",
+        params![node_id, ip, action, chain_id, dedup_key, message, attempts, created, next_attempt],
+    )?;
+    transaction.commit()
+}
+
+// Every waiting message, oldest first
+pub fn fetch_outbox() -> Result<Vec<OutboxEntry>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT id, node_id, ip, action, chain_id, dedup_key, message, attempts, created, next_attempt, last_error, error_code, failed FROM outbox ORDER BY id ASC")?;
+    let entries = statement.query_map([], |row| Ok(OutboxEntry{
+        id: row.get(0)?,
+        node_id: row.get(1)?,
+        ip: row.get(2)?,
+        action: row.get(3)?,
+        chain_id: row.get(4)?,
+        dedup_key: row.get(5)?,
+        message: row.get(6)?,
+        attempts: row.get(7)?,
+        created: row.get(8)?,
+        next_attempt: row.get(9)?,
+        last_error: row.get(10)?,
+        error_code: row.get(11)?,
+        failed: row.get(12)?,
+    }))?;
+    entries.collect()
+}
+
+// A message replaced while it was being delivered has a new id, so the replacement stays queued
+pub fn delete_outbox_entry(id: i64) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute("DELETE FROM outbox WHERE id = ?", params![id])?;
+    Ok(())
+}
+
+pub fn reschedule_outbox_entry(id: i64, attempts: i64, next_attempt: i64, error_code: &str, last_error: &str) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute("UPDATE outbox SET attempts = ?, next_attempt = ?, error_code = ?, last_error = ? WHERE id = ?", params![attempts, next_attempt, error_code, last_error, id])?;
+    Ok(())
+}
+
+pub fn fail_outbox_entry(id: i64, attempts: i64, error_code: &str, last_error: &str) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute("UPDATE outbox SET attempts = ?, failed = 1, error_code = ?, last_error = ? WHERE id = ?", params![attempts, error_code, last_error, id])?;
+    Ok(())
+}
+
+// ----- Provider Directory ----- //
+
+// Entries are checked before they get here, so this only replaces whatever we held for the provider
+pub fn insert_directory_entry(entry: &DirectoryEntry) -> Result<()> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO directory (node_id, public_key, name, organization, specialty, addresses, updated, signature)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        params![entry.node_id, entry.public_key, entry.name, entry.organization, entry.specialty, to_string(&entry.addresses).unwrap(), entry.updated, entry.signature],
+    )?;
+    Ok(())
+}
+
+pub fn fetch_directory_entry(node_id: &str) -> Result<Option<DirectoryEntry>> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT node_id, public_key, name, organization, specialty, addresses, updated, signature FROM directory WHERE node_id = ?",
+        params![node_id], directory_entry_from_row).optional()
+}
+
+pub fn count_directory_entries() -> Result<usize> {
+    let conn = Connection::open(DB_STRING)?;
+    conn.query_row("SELECT COUNT(*) FROM directory", [], |row| row.get(0))
+}
+
+// Remove the least recently updated entry dated before `updated`, other than `keep`. Returns whether one was removed.
+pub fn delete_oldest_directory_entry(keep: &str, updated: i64) -> Result<bool> {
+    let conn = Connection::open(DB_STRING)?;
+    let deleted = conn.execute(
+        "DELETE FROM directory WHERE node_id = (SELECT node_id FROM directory WHERE node_id != ? AND updated < ? ORDER BY updated ASC LIMIT 1)",
+        params![keep, updated],
+    )?;
+    Ok(deleted > 0)
+}
+
+pub fn fetch_directory() -> Result<Vec<DirectoryEntry>> {
+    let conn = Connection::open(DB_STRING)?;
+    let mut statement = conn.prepare("SELECT node_id, public_key, name, organization, specialty, addresses, updated, signature FROM directory")?;
+    let entries = statement.query_map([], directory_entry_from_row)?;
+    entries.collect()
+}
+
+fn directory_entry_from_row(row: &rusqlite::Row) -> Result<DirectoryEntry> {
+    let addresses: String = row.get(5)?;
+    Ok(DirectoryEntry{
+        node_id: row.get(0)?,
+        public_key: row.get(1)?,
+        name: row.get(2)?,
+        organization: row.get(3)?,
+        specialty: row.get(4)?,
+        addresses: from_str(&addresses).unwrap_or_default(),
+        updated: row.get(6)?,
+        signature: row.get(7)?,
+    })
+}
+
 // ------- Bootstrap Tables -------- //
 
 pub fn bootstrap() -> Result<()> {
@@ -281,18 +696,160 @@ fn create_tables(conn: &Connection) -> Result<()> {
             hash TEXT,
             provider_key TEXT,
             data_hash TEXT,
+            signature TEXT NOT NULL DEFAULT '',
+            version INTEGER NOT NULL DEFAULT 0,
             FOREIGN KEY (chain_id) REFERENCES chains(id),
             PRIMARY KEY (chain_id, id)
          )",
         [],
     )?;
 
+    // Columns added after the first release, for databases created before them
+    add_column_if_missing(conn, "blocks", "signature", "TEXT NOT NULL DEFAULT ''")?;
+    // Blocks stored before versioning used the legacy hash format, which is version 0
+    add_column_if_missing(conn, "blocks", "version", "INTEGER NOT NULL DEFAULT 0")?;
+    // Blocks stored before key epochs were all re-encrypted under the chain's latest key, which becomes epoch 0
+    add_column_if_missing(conn, "blocks", "key_epoch", "INTEGER NOT NULL DEFAULT 0")?;
+    // Id of the last block applied to the patient-state projection; -1 until a chain is projected
+    add_column_if_missing(conn, "chains", "projected_height", "INTEGER NOT NULL DEFAULT -1")?;
+    // Set for chains that failed the integrity check at startup
+    add_column_if_missing(conn, "chains", "integrity_failed", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Patient-state projection: the result of replaying each chain, kept up to date as blocks are inserted
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS providers (
+            chain_id TEXT,
+            block_id INTEGER,
+            name TEXT NOT NULL,
+            ip TEXT NOT NULL,
+            role TEXT NOT NULL DEFAULT 'contributor',
+            public_key TEXT NOT NULL DEFAULT '',
+            node_id TEXT NOT NULL DEFAULT '',
+            FOREIGN KEY (chain_id) REFERENCES chains(id),
+            PRIMARY KEY (chain_id, block_id)
+         )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS record_keys (
+            chain_id TEXT,
+            record_id INTEGER,
+            recipient TEXT NOT NULL,
+            key TEXT NOT NULL,
+            FOREIGN KEY (chain_id) REFERENCES chains(id),
+            PRIMARY KEY (chain_id, record_id, recipient)
+         )",
+        [],
+    )?;
+
+    // Projections written before provider roles and node ids need rebuilding to pick up each provider's role, key and node id
+    let added_role = add_column_if_missing(conn, "providers", "role", "TEXT NOT NULL DEFAULT 'contributor'")?;
+    let added_public_key = add_column_if_missing(conn, "providers", "public_key", "TEXT NOT NULL DEFAULT ''")?;
+    let added_node_id = add_column_if_missing(conn, "providers", "node_id", "TEXT NOT NULL DEFAULT ''")?;
+    if added_role || added_public_key || added_node_id {
+        conn.execute("UPDATE chains SET projected_height = -1", [])?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS records (
+            chain_id TEXT,
+            record_id INTEGER,
+            timestamp INTEGER,
+            subject TEXT NOT NULL,
+            status TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            details TEXT,
+            selected_readers INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (chain_id) REFERENCES chains(id),
+            PRIMARY KEY (chain_id, record_id)
+         )",
+        [],
+    )?;
+    // Records projected before readers could be picked are rebuilt to learn which ones were restricted
+    if add_column_if_missing(conn, "records", "selected_readers", "INTEGER NOT NULL DEFAULT 0")? {
+        conn.execute("UPDATE chains SET projected_height = -1", [])?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS record_revisions (
+            chain_id TEXT,
+            record_id INTEGER,
+            block_id INTEGER,
+            revision TEXT NOT NULL,
+            FOREIGN KEY (chain_id) REFERENCES chains(id),
+            PRIMARY KEY (chain_id, block_id)
+         )",
+        [],
+    )?;
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS shared_keys (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             chain_id TEXT,
             value BLOB,
-            active INTEGER
+            active INTEGER,
+            epoch INTEGER NOT NULL DEFAULT 0
+         )",
+        [],
+    )?;
+    add_column_if_missing(conn, "shared_keys", "epoch", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Keys of the attachments this node has encrypted
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachment_keys (
+            chain_id TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            value BLOB NOT NULL,
+            PRIMARY KEY (chain_id, hash)
+         )",
+        [],
+    )?;
+
+    // Keys for chains we don't hold yet, by the node that sent them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pending_keys (
+            chain_id TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            epoch INTEGER NOT NULL,
+            value BLOB NOT NULL,
+            PRIMARY KEY (chain_id, sender, epoch)
+         )",
+        [],
+    )?;
+
+    // Messages for peers that haven't been delivered yet, retried until they are
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            node_id TEXT NOT NULL,
+            ip TEXT NOT NULL,
+            action TEXT NOT NULL,
+            chain_id TEXT NOT NULL,
+            dedup_key TEXT NOT NULL,
+            message TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            created INTEGER NOT NULL,
+            next_attempt INTEGER NOT NULL,
+            last_error TEXT NOT NULL DEFAULT '',
+            UNIQUE (node_id, dedup_key)
+         )",
+        [],
+    )?;
+    add_column_if_missing(conn, "outbox", "error_code", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "outbox", "failed", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Signed listings of known providers, one per node, shared between peers
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS directory (
+            node_id TEXT PRIMARY KEY,
+            public_key TEXT NOT NULL,
+            name TEXT NOT NULL,
+            organization TEXT NOT NULL,
+            specialty TEXT NOT NULL,
+            addresses TEXT NOT NULL,
+            updated INTEGER NOT NULL,
+            signature TEXT NOT NULL
          )",
         [],
     )?;
@@ -307,4 +864,17 @@ fn create_tables(conn: &Connection) -> Result<()> {
     )?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Returns whether the column had to be added
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement.query_map([], |row| row.get::<usize, String>(1))?;
+    for existing in columns {
+        if existing? == column {
+            return Ok(false);
+        }
+    }
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    Ok(true)
+}
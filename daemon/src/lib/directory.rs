@@ -0,0 +1,212 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::{blockchain::{key_id, my_node_id, sign_bytes, verify_signature}, database::{count_directory_entries, delete_oldest_directory_entry, fetch_directory, fetch_directory_entry, insert_directory_entry, KeyPair}};
+
+// Limits that keep entries small, since every peer stores and passes on each one
+const MAX_FIELD_LENGTH: usize = 200;
+const MAX_ADDRESSES: usize = 8;
+// Room for a PEM RSA key of 4096 bits with some to spare
+const MAX_PUBLIC_KEY_LENGTH: usize = 1024;
+// Entries one directory-entries message may carry, and providers we keep entries for
+pub const MAX_ENTRIES_PER_MESSAGE: usize = 100;
+const MAX_DIRECTORY_ENTRIES: usize = 10_000;
+// How far ahead of our clock an entry's timestamp may be
+const MAX_CLOCK_SKEW: i64 = 60 * 60;
+
+// A provider's listing in the directory. It is signed with the key the provider signs blocks with, so only they can
+// publish or change it, and the node id is always worked out from that key rather than taken from the sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    #[serde(default)]
+    pub node_id: String,
+    pub public_key: String,
+    pub name: String,
+    #[serde(default)]
+    pub organization: String,
+    #[serde(default)]
+    pub specialty: String,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    // When the provider published this version; a newer version replaces an older one
+    pub updated: i64,
+    #[serde(default)]
+    pub signature: String
+}
+
+// The signed fields, each preceded by its length so no two entries sign the same bytes
+fn signed_bytes(entry: &DirectoryEntry) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![];
+    let updated = entry.updated.to_be_bytes();
+    let address_count = (entry.addresses.len() as u64).to_be_bytes();
+    let mut fields: Vec<&[u8]> = vec![
+        entry.public_key.as_bytes(),
+        entry.name.as_bytes(),
+        entry.organization.as_bytes(),
+        entry.specialty.as_bytes(),
+        &updated,
+        &address_count
+    ];
+    fields.extend(entry.addresses.iter().map(|address| address.as_bytes()));
+    for field in fields {
+        bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(field);
+    }
+    bytes
+}
+
+// Our own entry, signed and timestamped now
+pub fn sign_entry(name: String, organization: String, specialty: String, addresses: Vec<String>, key_pair: &KeyPair) -> Result<DirectoryEntry, String> {
+    let mut entry = DirectoryEntry{
+        node_id: key_id(&key_pair.public_key),
+        public_key: key_pair.public_key.clone(),
+        name,
+        organization,
+        specialty,
+        addresses,
+        updated: Utc::now().timestamp(),
+        signature: String::new()
+    };
+    // Always newer than what we published before, even within the same second
+    if let Ok(Some(existing)) = fetch_directory_entry(&entry.node_id) {
+        entry.updated = entry.updated.max(existing.updated + 1);
+    }
+    check_fields(&entry)?;
+    entry.signature = sign_bytes(&signed_bytes(&entry), &key_pair.private_key);
+    Ok(entry)
+}
+
+fn check_fields(entry: &DirectoryEntry) -> Result<(), String> {
+    if entry.name.trim().is_empty() {
+        return Err("A directory entry needs a name".to_string());
+    }
+    let mut fields = [&entry.name, &entry.organization, &entry.specialty].into_iter().chain(entry.addresses.iter());
+    if fields.any(|field| field.len() > MAX_FIELD_LENGTH) {
+        return Err(format!("Directory entry fields are limited to {} bytes", MAX_FIELD_LENGTH));
+    }
+    if entry.addresses.len() > MAX_ADDRESSES {
+        return Err(format!("A directory entry can list at most {} addresses", MAX_ADDRESSES));
+    }
+    if entry.public_key.len() > MAX_PUBLIC_KEY_LENGTH {
+        return Err(format!("Directory entry public keys are limited to {} bytes", MAX_PUBLIC_KEY_LENGTH));
+    }
+    Ok(())
+}
+
+pub fn verify_entry(entry: &DirectoryEntry) -> Result<(), String> {
+    check_fields(entry)?;
+    if entry.updated > Utc::now().timestamp() + MAX_CLOCK_SKEW {
+        return Err("Directory entry is dated in the future".to_string());
+    }
+    if !verify_signature(&signed_bytes(entry), &entry.signature, &entry.public_key) {
+        return Err("Invalid directory entry signature".to_string());
+    }
+    Ok(())
+}
+
+// Store an entry if it is valid and newer than the one we hold for that provider. Returns the entry if it was stored,
+// so callers only pass on entries that were new to us. Once the directory is full, a new provider takes the place of
+// the entry updated longest ago, as long as theirs is newer. Our own entry is never dropped.
+pub fn store_entry(mut entry: DirectoryEntry) -> Result<Option<DirectoryEntry>, String> {
+    verify_entry(&entry)?;
+    entry.node_id = key_id(&entry.public_key);
+    match fetch_directory_entry(&entry.node_id) {
+        Ok(Some(existing)) if existing.updated >= entry.updated => return Ok(None),
+        Ok(Some(_)) => {},
+        _ => {
+            if count_directory_entries().map_err(|err| err.to_string())? >= MAX_DIRECTORY_ENTRIES
+                && !delete_oldest_directory_entry(&my_node_id(), entry.updated).map_err(|err| err.to_string())? {
+                return Err(format!("The directory already holds {} newer entries", MAX_DIRECTORY_ENTRIES));
+            }
+        }
+    }
+    insert_directory_entry(&entry).map(|_| Some(entry)).map_err(|err| err.to_string())
+}
+
+// Entries whose name, organization or specialty contain the query, ignoring case. A specialty narrows the results
+// to providers of exactly that specialty.
+pub fn search_entries(query: &str, specialty: Option<&str>) -> Result<Vec<DirectoryEntry>, String> {
+    let query = query.trim().to_lowercase();
+    let mut entries: Vec<DirectoryEntry> = fetch_directory().map_err(|err| err.to_string())?.into_iter()
+        .filter(|entry| specialty.map(|specialty| entry.specialty.eq_ignore_ascii_case(specialty.trim())).unwrap_or(true))
+        .filter(|entry| query.is_empty() || [&entry.name, &entry.organization, &entry.specialty].iter().any(|field| field.to_lowercase().contains(&query)))
+        .collect();
+    entries.sort_by_key(|entry| entry.name.to_lowercase());
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::generate_key_pair;
+
+    fn signed_entry(key_pair: &KeyPair) -> DirectoryEntry {
+        let mut entry = DirectoryEntry{
+            node_id: key_id(&key_pair.public_key),
+            public_key: key_pair.public_key.clone(),
+            name: "Dr Lee".to_string(),
+            organization: "Clinic".to_string(),
+            specialty: "cardiology".to_string(),
+            addresses: vec!["10.0.0.1".to_string()],
+            updated: Utc::now().timestamp(),
+            signature: String::new()
+        };
+        entry.signature = sign_bytes(&signed_bytes(&entry), &key_pair.private_key);
+        entry
+    }
+
+    #[test]
+    fn signed_bytes_keep_fields_apart() {
+        let key_pair = generate_key_pair();
+        let entry = signed_entry(&key_pair);
+        let mut moved = entry.clone();
+        moved.name = "Dr Le".to_string();
+        moved.organization = "eClinic".to_string();
+        assert_ne!(signed_bytes(&entry), signed_bytes(&moved));
+
+        let mut split = entry.clone();
+        split.addresses = vec!["10.0.0".to_string(), "1".to_string()];
+        assert_ne!(signed_bytes(&entry), signed_bytes(&split));
+    }
+
+    #[test]
+    fn verify_entry_accepts_an_entry_signed_by_its_key() {
+        let key_pair = generate_key_pair();
+        assert!(verify_entry(&signed_entry(&key_pair)).is_ok());
+    }
+
+    #[test]
+    fn verify_entry_refuses_changed_or_resigned_entries() {
+        let key_pair = generate_key_pair();
+        let mut changed = signed_entry(&key_pair);
+        changed.specialty = "surgery".to_string();
+        assert!(verify_entry(&changed).is_err());
+
+        // Signed by another key than the one the entry lists
+        let mut resigned = signed_entry(&key_pair);
+        resigned.signature = sign_bytes(&signed_bytes(&resigned), &generate_key_pair().private_key);
+        assert!(verify_entry(&resigned).is_err());
+    }
+
+    #[test]
+    fn verify_entry_refuses_future_and_oversized_entries() {
+        let key_pair = generate_key_pair();
+        let mut future = signed_entry(&key_pair);
+        future.updated += MAX_CLOCK_SKEW + 60;
+        future.signature = sign_bytes(&signed_bytes(&future), &key_pair.private_key);
+        assert!(verify_entry(&future).is_err());
+
+        let mut long_name = signed_entry(&key_pair);
+        long_name.name = "x".repeat(MAX_FIELD_LENGTH + 1);
+        long_name.signature = sign_bytes(&signed_bytes(&long_name), &key_pair.private_key);
+        assert!(verify_entry(&long_name).is_err());
+
+        let mut many_addresses = signed_entry(&key_pair);
+        many_addresses.addresses = vec!["10.0.0.1".to_string(); MAX_ADDRESSES + 1];
+        many_addresses.signature = sign_bytes(&signed_bytes(&many_addresses), &key_pair.private_key);
+        assert!(verify_entry(&many_addresses).is_err());
+
+        let mut long_key = signed_entry(&key_pair);
+        long_key.public_key.push_str(&" ".repeat(MAX_PUBLIC_KEY_LENGTH));
+        assert!(check_fields(&long_key).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Mutex;
+use chrono::Utc;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use crate::{blockchain::my_node_id, network::DEFAULT_PORT};
+
+// DNS-SD service type EHR nodes advertise themselves under on the local network
+const SERVICE_TYPE: &str = "_ehr._tcp.local.";
+// Nodes kept in the list. Once it is full, the one heard from longest ago makes way for a new one.
+const MAX_DISCOVERED: usize = 256;
+
+// A node found on the local network. Its name and node id are what it says about itself; the node id is only
+// proven once its public key is fetched over TLS, when it is added as a provider.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredProvider {
+    pub node_id: String,
+    pub name: String,
+    pub ip: String,
+    pub last_seen: i64,
+    #[serde(skip)]
+    fullname: String
+}
+
+static DISCOVERED: Mutex<Vec<DiscoveredProvider>> = Mutex::new(Vec::new());
+
+// Setting EHR_DISCOVERY to "off" keeps the node from advertising itself or browsing for others
+fn discovery_enabled() -> bool {
+    std::env::var("EHR_DISCOVERY").map(|value| value != "off").unwrap_or(true)
+}
+
+// The name other nodes list us under, from EHR_DISPLAY_NAME
+fn display_name(node_id: &str) -> String {
+    match std::env::var("EHR_DISPLAY_NAME") {
+        Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => format!("EHR node {}", &node_id[..8])
+    }
+}
+
+// Advertise this node over mDNS and keep the list of other nodes up to date. Runs for the life of the daemon.
+pub async fn advertise_and_browse() {
+    if !discovery_enabled() {
+        return;
+    }
+    let mdns = match ServiceDaemon::new() {
+        Ok(mdns) => mdns,
+        Err(err) => {
+            eprintln!("Unable to start provider discovery: {}", err);
+            return;
+        }
+    };
+
+    // Display names needn't be unique, so the instance is named after the node id; DNS labels are at most 63 bytes
+    let node_id = my_node_id();
+    let instance = &node_id[..32];
+    let name = display_name(&node_id);
+    let properties = [("node_id", node_id.as_str()), ("name", name.as_str())];
+    let service = ServiceInfo::new(SERVICE_TYPE, instance, &format!("ehr-{}.local.", instance), "", DEFAULT_PORT as u16, &properties[..])
+        .map(ServiceInfo::enable_addr_auto);
+    if let Err(err) = service.and_then(|service| mdns.register(service)) {
+        eprintln!("Unable to advertise this node: {}", err);
+    }
+
+    let receiver = match mdns.browse(SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(err) => {
+            eprintln!("Unable to browse for providers: {}", err);
+            return;
+        }
+    };
+    while let Ok(event) = receiver.recv_async().await {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let found_id = service.get_property_val_str("node_id").unwrap_or_default().to_string();
+                let ip = match service.get_addresses_v4().into_iter().next() {
+                    Some(ip) => ip.to_string(),
+                    None => continue
+                };
+                // Peers are always contacted on the P2P port, so a node advertising another one couldn't be reached
+                if found_id.is_empty() || found_id == node_id || service.get_port() != DEFAULT_PORT as u16 {
+                    continue;
+                }
+                let provider = DiscoveredProvider{
+                    name: service.get_property_val_str("name").unwrap_or_default().to_string(),
+                    node_id: found_id,
+                    ip,
+                    last_seen: Utc::now().timestamp(),
+                    fullname: service.get_fullname().to_string()
+                };
+                let mut discovered = DISCOVERED.lock().unwrap();
+                discovered.retain(|known| known.fullname != provider.fullname);
+                if discovered.len() >= MAX_DISCOVERED {
+                    if let Some(oldest) = discovered.iter().enumerate().min_by_key(|(_, known)| known.last_seen).map(|(index, _)| index) {
+                        discovered.remove(oldest);
+                    }
+                }
+                discovered.push(provider);
+            },
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                DISCOVERED.lock().unwrap().retain(|known| known.fullname != fullname);
+            },
+            _ => {}
+        }
+    }
+}
+
+// Nodes currently advertising on the local network, by name
+pub fn discovered_providers() -> Vec<DiscoveredProvider> {
+    let mut discovered = DISCOVERED.lock().unwrap().clone();
+    discovered.sort_by_key(|provider| provider.name.to_lowercase());
+    discovered
+}
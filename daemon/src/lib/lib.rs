@@ -1,4 +1,9 @@
 pub mod database;
 pub mod socket;
 pub mod network;
-pub mod blockchain;
\ No newline at end of file
+pub mod blockchain;
+pub mod payload;
+pub mod attachment;
+pub mod tls;
+pub mod discovery;
+pub mod directory;
@@ -1,14 +1,32 @@
-use std::{io::{Cursor, Read, Write}, net::{TcpListener, TcpStream}, str::from_utf8, sync::Arc};
-use local_ip_address::local_ip;
-use openssl::pkey::PKey;
-use rcgen::generate_simple_self_signed;
-use rustls::{client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, crypto::aws_lc_rs::sign::any_supported_type, pki_types::{CertificateDer, PrivateKeyDer}, server::ResolvesServerCert, ServerConfig};
-use serde_json::{from_str, from_value, to_string, to_value, Map, Value};
+use std::{collections::{HashMap, HashSet}, future::Future, io::{Error, ErrorKind}, sync::{Arc, Mutex}, time::Duration};
+use serde_json::{from_slice, from_str, from_value, json, to_string, to_value, Map, Value};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Receiver;
-use crate::{blockchain::{add_block, get_active_providers, reencrypt_block, Block}, database::{chain_exists, fetch_all_blocks, get_key_pair, get_shared_key, insert_new_shared_key, set_chain_active, update_block}};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::{mpsc::Receiver, Notify, Semaphore}, task::JoinSet, time::timeout};
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+use rustc_serialize::hex::{FromHex, ToHex};
+use chrono::Utc;
+use crate::{attachment::{list_attachments, read_encrypted_attachment, write_attachment_chunk, ATTACHMENT_CHUNK_SIZE}, blockchain::{add_block, add_new_chain, attachment_keys, withheld_attachments, get_active_providers, key_id, my_node_id, node_role, unwrap_key, wrap_key, Block}, database::{chain_exists, is_chain_integrity_failed, fetch_block_hash, fetch_blocks_from, fetch_chain_ids, fetch_last_block, is_chain_active, fetch_epoch_keys, fetch_provider_public_keys, fetch_directory, fetch_directory_entry, fetch_outbox, queue_outbox, delete_outbox_entry, reschedule_outbox_entry, fail_outbox_entry, OutboxEntry, get_current_epoch, get_epoch_key, get_key_pair, get_shared_key, insert_shared_key, insert_pending_key, count_pending_key_chains, set_chain_active}, directory::{store_entry, DirectoryEntry, MAX_ENTRIES_PER_MESSAGE}, discovery::advertise_and_browse, payload::ProviderRole, tls::{client_config, peer_node_id, server_config, trust_mode, TrustMode}};
 
-const DEFAULT_PORT: i32 = 8047;
+pub const DEFAULT_PORT: i32 = 8047;
+// How long to wait for a TCP connection and TLS handshake with a peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long one request may take from either side, so a slow or silent peer only holds up its own connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Largest message accepted from a peer unless EHR_MAX_MESSAGE_SIZE gives another size in bytes
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// Peer connections served at once. Further peers wait to be accepted until one closes.
+const MAX_CONNECTIONS: usize = 64;
+// Chain updates are sent as batches of blocks of about this many bytes, over one connection
+const BLOCK_BATCH_SIZE: usize = 256 * 1024;
+// Bytes of block data held back per connection for chains we don't hold yet, until they show we were added
+const MAX_PENDING_CHAIN_SIZE: usize = 64 * 1024 * 1024;
+// Chains we don't hold yet that one node may have sent us keys for
+const MAX_PENDING_KEY_CHAINS: usize = 100;
+// How often the outbox is checked for messages due to be retried
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Seconds to wait before retrying a message after its first failed attempt, doubling after each one after that
+const OUTBOX_RETRY_DELAY: i64 = 10;
+const OUTBOX_MAX_RETRY_DELAY: i64 = 60 * 60;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct P2PRequest {
@@ -16,12 +34,114 @@ pub struct P2PRequest {
     pub parameters: Map<String, Value>
 }
 
+// A refused request carries an error saying why, and may still carry data, such as how many blocks of an update were
+// added before one was rejected.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct P2PResponse {
     pub ok: bool,
     pub data: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<P2PError>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct P2PError {
+    pub code: P2PErrorCode,
+    pub message: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum P2PErrorCode {
+    // Reported by the sender when the peer couldn't be reached or didn't answer in time
+    Unreachable,
+    // Reported by the sender when the peer's reply couldn't be read
+    InvalidResponse,
+    InvalidRequest,
+    UnknownAction,
+    Unauthorized,
+    UnknownChain,
+    InvalidBlock,
+    // A different key is already held for the epoch
+    StaleKey,
+    // The peer doesn't hold the key needed to open what was sent yet
+    MissingKey,
+    // A key sent to the peer couldn't be unwrapped with its private key, so it was wrapped for someone else
+    InvalidKey,
+    InvalidAttachment,
+    // The peer's copy of the chain doesn't share our head
+    Diverged,
+    // One side's copy of the chain failed its integrity check, so it isn't synced
+    IntegrityFailed,
+    Internal,
+    // Sent by peers that don't know a code the sender uses
+    #[serde(other)]
+    Unknown
+}
+
+impl P2PErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            P2PErrorCode::Unreachable => "unreachable",
+            P2PErrorCode::InvalidResponse => "invalid_response",
+            P2PErrorCode::InvalidRequest => "invalid_request",
+            P2PErrorCode::UnknownAction => "unknown_action",
+            P2PErrorCode::Unauthorized => "unauthorized",
+            P2PErrorCode::UnknownChain => "unknown_chain",
+            P2PErrorCode::InvalidBlock => "invalid_block",
+            P2PErrorCode::StaleKey => "stale_key",
+            P2PErrorCode::MissingKey => "missing_key",
+            P2PErrorCode::InvalidKey => "invalid_key",
+            P2PErrorCode::InvalidAttachment => "invalid_attachment",
+            P2PErrorCode::Diverged => "diverged",
+            P2PErrorCode::IntegrityFailed => "integrity_failed",
+            P2PErrorCode::Internal => "internal",
+            P2PErrorCode::Unknown => "unknown"
+        }
+    }
+
+    // Failures that may clear up on their own are retried; the rest would be refused again, so they aren't
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, P2PErrorCode::Unreachable | P2PErrorCode::InvalidResponse | P2PErrorCode::MissingKey | P2PErrorCode::Internal | P2PErrorCode::Unknown)
+    }
+}
+
+impl std::fmt::Display for P2PError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code.as_str())
+    }
+}
+
+// How far the catch-up with other providers at startup has got
+#[derive(Debug, Clone, Serialize)]
+pub struct CatchUpProgress {
+    pub running: bool,
+    pub chains: Vec<ChainCatchUp>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainCatchUp {
+    pub chain_id: String,
+    // One of "waiting", "syncing" or "done"
+    pub status: String,
+    // Blocks held before catching up, and now
+    pub start_length: i64,
+    pub length: i64
+}
+
+// Messages waiting in the outbox for one peer, oldest first
+#[derive(Debug, Serialize)]
+pub struct PeerOutbox {
+    pub node_id: String,
+    pub ip: String,
+    pub messages: Vec<OutboxEntry>
+}
+
+static CATCH_UP_PROGRESS: Mutex<CatchUpProgress> = Mutex::new(CatchUpProgress{ running: false, chains: Vec::new() });
+
+// Wakes the outbox worker when a message is queued
+static OUTBOX_READY: Notify = Notify::const_new();
+
 pub async fn initialize_p2p_thread(receiver_from_blockchain: Receiver<String>) {
 
     let blockchain_listener = tokio::spawn(async move {
@@ -32,81 +152,24 @@ pub async fn initialize_p2p_thread(receiver_from_blockchain: Receiver<String>) {
         handle_request_from_network().await;
     });
 
+    let outbox_worker = tokio::spawn(async move {
+        deliver_outbox().await;
+    });
+
+    let discovery = tokio::spawn(async move {
+        advertise_and_browse().await;
+    });
+
     // Wait for threads
-    if let Err(err) = tokio::try_join!(blockchain_listener, network_listener) {
+    if let Err(err) = tokio::try_join!(blockchain_listener, network_listener, outbox_worker, discovery) {
         eprintln!("Error running tasks: {:?}", err);
     }
 
 }
 
-#[derive(Debug)]
-struct AllowAnyCertVerifier;
-
-impl ServerCertVerifier for AllowAnyCertVerifier {
-    fn verify_server_cert(
-            &self,
-            _: &CertificateDer<'_>,
-            _: &[CertificateDer<'_>],
-            _: &rustls::pki_types::ServerName<'_>,
-            _: &[u8],
-            _: rustls::pki_types::UnixTime,
-        ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(ServerCertVerified::assertion())
-    }
-    
-    fn verify_tls12_signature(
-        &self,
-        _: &[u8],
-        _: &CertificateDer<'_>,
-        _: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
-    }
-    
-    fn verify_tls13_signature(
-        &self,
-        _: &[u8],
-        _: &CertificateDer<'_>,
-        _: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
-    }
-    
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        vec![rustls::SignatureScheme::RSA_PKCS1_SHA1,
-        rustls::SignatureScheme::ECDSA_SHA1_Legacy,
-        rustls::SignatureScheme::RSA_PKCS1_SHA256,
-        rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
-        rustls::SignatureScheme::RSA_PKCS1_SHA384,
-        rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
-        rustls::SignatureScheme::RSA_PKCS1_SHA512,
-        rustls::SignatureScheme::ECDSA_NISTP521_SHA512,
-        rustls::SignatureScheme::RSA_PSS_SHA256,
-        rustls::SignatureScheme::RSA_PSS_SHA384,
-        rustls::SignatureScheme::RSA_PSS_SHA512,
-        rustls::SignatureScheme::ED25519,
-        rustls::SignatureScheme::ED448]
-    }
-}
-
-// Need to allow any cert to get around certificate authorities for now
-impl ResolvesServerCert for AllowAnyCertVerifier {
-    fn resolve(&self, _: rustls::server::ClientHello) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        // Create a dummy server certificate and private key
-        let cert_key = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
-        let cert_der = cert_key.cert.der().to_owned();
-    
-        // Return the dummy server certificate and private key as a CertifiedKey
-        Some(Arc::new(rustls::sign::CertifiedKey::new(vec![cert_der], any_supported_type(&PrivateKeyDer::Pkcs8(cert_key.key_pair.serialized_der().into())).unwrap())))
-    }
-}
-
 async fn handle_request_from_network(){
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_cert_resolver(Arc::new(AllowAnyCertVerifier));
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", DEFAULT_PORT));
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", DEFAULT_PORT)).await;
 
     let listener = match listener {
         Ok(listener) => listener,
@@ -116,20 +179,143 @@ async fn handle_request_from_network(){
         },
     };
 
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
     loop {
-        let (stream, _) = listener.accept().unwrap();
-        let conn = rustls::ServerConnection::new(Arc::new(config.clone())).unwrap();
-        let mut tls = rustls::StreamOwned::new(conn, stream);
-        
-        let mut buf = [0; 32896];
+        let permit = connections.clone().acquire_owned().await.unwrap();
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("Unable to accept P2P connection: {}", err);
+                continue;
+            }
+        };
+        // Each connection is served on its own task, which holds its permit until the connection closes
+        tokio::spawn(async move {
+            handle_connection(stream, address.to_string()).await;
+            drop(permit);
+        });
+    }
+}
+
+// What the server knows about a connection across the requests sent over it
+struct Session {
+    // The node id the sender proved during the TLS handshake
+    peer: String,
+    // Whether the sender may update each chain, decided by the first batch of blocks for it
+    authorized: HashMap<String, bool>,
+    // Blocks of chains we don't hold yet, kept until they include the block that added us
+    pending: HashMap<String, Vec<Block>>,
+    pending_size: usize
+}
+
+async fn handle_connection(stream: TcpStream, address: String) {
+    // Read per connection so changes to the trust files, such as a new revocation list, apply straight away.
+    // The peer's node id is then read from its certificate under the same trust mode it was verified with.
+    let trust = match trust_mode() {
+        Ok(trust) => trust,
+        Err(err) => {
+            eprintln!("Unable to accept P2P connection: {}", err);
+            return;
+        }
+    };
+    let acceptor = match server_config(&trust) {
+        Ok(config) => TlsAcceptor::from(Arc::new(config)),
+        Err(err) => {
+            eprintln!("Unable to accept P2P connection: {}", err);
+            return;
+        }
+    };
+    // Peers that fail the mutual TLS handshake never get as far as a request
+    let mut tls = match timeout(CONNECT_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(tls)) => tls,
+        Ok(Err(err)) => {
+            eprintln!("Rejected P2P connection from {}: {}", address, err);
+            return;
+        },
+        Err(_) => {
+            eprintln!("Dropped P2P connection from {}: timed out", address);
+            return;
+        }
+    };
+    let peer = match peer_node_id(&trust, tls.get_ref().1.peer_certificates()) {
+        Some(peer) => peer,
+        None => return
+    };
+
+    // A peer may send several requests, one frame each, before closing the connection
+    let mut session = Session{ peer, authorized: HashMap::new(), pending: HashMap::new(), pending_size: 0 };
+    loop {
+        let frame = match timeout(REQUEST_TIMEOUT, read_frame(&mut tls)).await {
+            Ok(Ok(Some(frame))) => frame,
+            Ok(Ok(None)) => break,
+            Ok(Err(err)) => {
+                eprintln!("Unable to read request from {}: {}", session.peer, err);
+                break;
+            },
+            Err(_) => {
+                eprintln!("Dropped P2P connection from {}: timed out", session.peer);
+                break;
+            }
+        };
+        let request: P2PRequest = match from_slice(&frame) {
+            Ok(request) => request,
+            Err(err) => {
+                eprintln!("Refused malformed request from {}: {}", session.peer, err);
+                break;
+            }
+        };
+        // Requests go to the database, so they run off the async workers
+        let response = match tokio::task::spawn_blocking(move || {
+            let response = handle_request(request, &mut session);
+            (response, session)
+        }).await {
+            Ok((response, returned)) => {
+                session = returned;
+                response
+            },
+            Err(_) => return
+        };
+        if write_frame(&mut tls, to_string(&response).unwrap().as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    let _ = tls.shutdown().await;
+}
 
-        let len = tls.read(&mut buf).unwrap();
+// Messages are framed by a 4-byte big-endian length, so they can be any size up to the limit and span TLS records
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> std::io::Result<()> {
+    let length = u32::try_from(message.len()).map_err(|_| Error::new(ErrorKind::InvalidInput, "message too large"))?;
+    stream.write_all(&length.to_be_bytes()).await?;
+    stream.write_all(message).await?;
+    stream.flush().await
+}
 
-        let network_request_str = from_utf8(&buf[0..len]).unwrap();
-        let request: P2PRequest = from_str(network_request_str).unwrap();
-        let response = handle_request(request);
-        let _ = tls.write_all(to_string(&response).unwrap().as_bytes());
+// None when the peer closed the connection between messages
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {},
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err)
     }
+    let length = u32::from_be_bytes(length) as usize;
+    let max_size = max_message_size();
+    if length > max_size {
+        return Err(Error::new(ErrorKind::InvalidData, format!("message of {} bytes is over the {} byte limit", length, max_size)));
+    }
+    // The buffer grows as bytes arrive, so a peer can't make us allocate the limit just by announcing it
+    let mut message = Vec::new();
+    stream.take(length as u64).read_to_end(&mut message).await?;
+    if message.len() < length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, format!("message ended after {} of {} bytes", message.len(), length)));
+    }
+    Ok(Some(message))
+}
+
+pub fn max_message_size() -> usize {
+    std::env::var("EHR_MAX_MESSAGE_SIZE").ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
 }
 
 async fn handle_request_from_blockchain(mut receiver_from_blockchain: Receiver<String>) {
@@ -139,96 +325,339 @@ async fn handle_request_from_blockchain(mut receiver_from_blockchain: Receiver<S
             let blockchain_request: P2PRequest = from_str(&msg).unwrap();
 
             match blockchain_request.action.as_str() {
-                "add-provider" => add_remote_provider( blockchain_request.parameters.get("ip").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string()),
-                "remove-provider" => remove_remote_provider(blockchain_request.parameters.get("ip").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string()),
-                "add-record" => add_record(blockchain_request.parameters),
+                "add-provider" => add_remote_provider( blockchain_request.parameters.get("ip").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("public_key").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string()),
+                "remove-provider" => remove_remote_provider(blockchain_request.parameters.get("node_id").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("ip").unwrap().as_str().unwrap().to_string(), blockchain_request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string()),
+                "add-record" | "amend-record" | "retract-record" | "share-records" | "update-provider-address" => add_record(blockchain_request.parameters),
                 "send_new_shared_key" => send_new_shared_key(blockchain_request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string()),
+                "share-directory-entries" => match blockchain_request.parameters.get("entries").map(|entries| from_value::<Vec<DirectoryEntry>>(entries.clone())) {
+                    Some(Ok(entries)) => queue_directory_entries(&entries, ""),
+                    _ => eprintln!("Unable to share directory entries: malformed entries")
+                },
                 _ => {}
             }
         }
     }
 }
 
-fn connect_to_host(ip: String) -> Option<rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream>>{
-    let keys = get_key_pair().unwrap();
-    if let Some(key_pair) = keys {
-        let rsa_pkey_bytes = key_pair.private_key.clone();
-        let ssl_pkey = PKey::private_key_from_pkcs8(&rsa_pkey_bytes).unwrap();
-        let pem = String::from_utf8(ssl_pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
-        let mut cursor = Cursor::new(pem);
-        
-        let private_key = rustls_pemfile::private_key(&mut cursor).unwrap().unwrap();
-        let private_key_clone = private_key.clone_key();
-        
-        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-        let cert = cert.cert;
-        let cert_der = cert.der().to_owned();
-        let cert_clone = cert_der.clone();
-
-        let config = rustls::ClientConfig::builder()
-        .dangerous().with_custom_certificate_verifier(Arc::new(AllowAnyCertVerifier))
-        .with_client_auth_cert(vec![cert_clone], private_key_clone)
-        .unwrap();
-    
-        let server_name = "localhost".try_into().unwrap();
-        let conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
-        let sock_attempt = TcpStream::connect(format!("{}:8047", ip));
-        match sock_attempt {
-            Ok(socket) => {
-                let tls = rustls::StreamOwned::new(conn, socket);
-                    Some(tls)
-                },
-            Err(_) => None,
+// Connect to a node over mutual TLS. With a node id, the connection fails unless the node proves it holds that id's key.
+async fn connect_to_host(trust: &TrustMode, node_id: Option<&str>, ip: String) -> Result<client::TlsStream<TcpStream>, P2PError> {
+    let config = client_config(trust, node_id).map_err(|err| p2p_error(P2PErrorCode::Internal, err))?;
+
+    let connector = TlsConnector::from(Arc::new(config));
+    let server_name = "localhost".try_into().unwrap();
+    let connection = async {
+        let socket = TcpStream::connect(format!("{}:{}", ip, DEFAULT_PORT)).await?;
+        connector.connect(server_name, socket).await
+    };
+    match timeout(CONNECT_TIMEOUT, connection).await {
+        Ok(Ok(tls)) => Ok(tls),
+        Ok(Err(err)) => Err(p2p_error(P2PErrorCode::Unreachable, format!("Unable to connect to {}: {}", ip, err))),
+        Err(_) => Err(p2p_error(P2PErrorCode::Unreachable, format!("Unable to connect to {}: timed out", ip)))
+    }
+}
+
+// Send one request and wait for the reply
+async fn exchange(tls: &mut client::TlsStream<TcpStream>, request: &P2PRequest) -> std::io::Result<Vec<u8>> {
+    write_frame(tls, to_string(&request).unwrap().as_bytes()).await?;
+    match read_frame(tls).await? {
+        Some(response) => Ok(response),
+        None => Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before a response"))
+    }
+}
+
+async fn request_remote(node_id: &str, ip: String, request: &P2PRequest) -> Result<Value, P2PError> {
+    let mut tls = open_remote(node_id, &ip).await?;
+    let response = send_request(&mut tls, &ip, request).await;
+    let _ = tls.shutdown().await;
+    accepted(response?)
+}
+
+// Callers leave our own node out of the providers they contact, so every connection here goes over the network.
+// Providers added before node ids have none to pin the connection to, so they can't be reached.
+async fn open_remote(node_id: &str, ip: &str) -> Result<client::TlsStream<TcpStream>, P2PError> {
+    if node_id.is_empty() {
+        return Err(p2p_error(P2PErrorCode::Unreachable, format!("Not contacting provider at {}: no public key is recorded for it", ip)));
+    }
+    let trust = trust_mode().map_err(|err| p2p_error(P2PErrorCode::Internal, err))?;
+    connect_to_host(&trust, Some(node_id), ip.to_string()).await
+}
+
+// Send one request over an open connection, which can carry any number of them in turn, and return the reply
+async fn send_request(tls: &mut client::TlsStream<TcpStream>, ip: &str, request: &P2PRequest) -> Result<P2PResponse, P2PError> {
+    let response = match timeout(REQUEST_TIMEOUT, exchange(tls, request)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => return Err(p2p_error(P2PErrorCode::Unreachable, format!("Unable to send {} to provider at {}: {}", request.action, ip, err))),
+        Err(_) => return Err(p2p_error(P2PErrorCode::Unreachable, format!("No response from provider at {}: timed out", ip)))
+    };
+    from_slice(&response).map_err(|err| p2p_error(P2PErrorCode::InvalidResponse, format!("Malformed response to {} from provider at {}: {}", request.action, ip, err)))
+}
+
+// The reply's data if the request was applied. A refusal without an error doesn't say why, so it is retried.
+fn accepted(response: P2PResponse) -> Result<Value, P2PError> {
+    match response {
+        P2PResponse{ ok: true, data, .. } => Ok(data),
+        P2PResponse{ error: Some(error), .. } => Err(error),
+        P2PResponse{ error: None, .. } => Err(p2p_error(P2PErrorCode::InvalidResponse, "request refused without an error".to_string()))
+    }
+}
+
+// Providers are contacted concurrently, so one slow or unreachable provider only delays itself
+async fn for_each_provider<F, Fut>(providers: Vec<(String, String)>, send: F)
+where
+    F: Fn(String, String) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static
+{
+    let mut tasks = JoinSet::new();
+    for (node_id, ip) in providers {
+        tasks.spawn(send(node_id, ip));
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+// Ask the node at an address for the public key it signs blocks with
+pub async fn fetch_remote_public_key(ip: String) -> Result<String, String> {
+    let request = P2PRequest{
+        action: "get-public-key".to_string(),
+        parameters: Map::new()
+    };
+    let trust = trust_mode()?;
+    let mut tls = match connect_to_host(&trust, None, ip.clone()).await {
+        Ok(tls) => tls,
+        Err(_) => return Err(format!("Unable to reach provider at {}", ip))
+    };
+    let response = match timeout(REQUEST_TIMEOUT, exchange(&mut tls, &request)).await {
+        Ok(Ok(response)) => response,
+        _ => return Err(format!("No response from provider at {}", ip))
+    };
+    // The key the node reports must give the node id it proved during the handshake
+    let peer = peer_node_id(&trust, tls.get_ref().1.peer_certificates());
+    match from_slice::<P2PResponse>(&response) {
+        Ok(P2PResponse{ ok: true, data: Value::String(public_key), .. }) if Some(key_id(&public_key)) == peer => Ok(public_key),
+        Ok(P2PResponse{ ok: true, data: Value::String(_), .. }) => Err(format!("Provider at {} returned a public key that does not match its certificate", ip)),
+        _ => Err(format!("Provider at {} did not return a public key", ip))
+    }
+}
+
+// ----- Outbox ----- //
+
+// Messages for peers are queued in the database and delivered from there, so a peer that is offline gets them once it
+// is back, even after a restart. The dedup key names what a message is about, so a newer message about the same thing
+// replaces one that hasn't been delivered yet.
+fn queue_message(node_id: &str, ip: &str, action: &str, chain_id: &str, dedup_key: &str, message: &str) {
+    if node_id.is_empty() {
+        eprintln!("Not contacting provider at {}: no public key is recorded for it", ip);
+        return;
+    }
+    if let Err(err) = queue_outbox(node_id, ip, action, chain_id, dedup_key, message, Utc::now().timestamp()) {
+        eprintln!("Unable to queue {} for {}: {}", action, ip, err);
+        return;
+    }
+    OUTBOX_READY.notify_one();
+}
+
+fn queue_request(node_id: &str, ip: &str, chain_id: &str, dedup_key: &str, request: &P2PRequest) {
+    queue_message(node_id, ip, &request.action, chain_id, dedup_key, &to_string(request).unwrap());
+}
+
+// Chains are synced by comparing heads when the message is delivered, so one queued sync covers every change before it
+fn queue_chain_sync(chain_id: &str) {
+    if check_integrity(chain_id).is_err() {
+        return;
+    }
+    for (node_id, ip) in get_active_providers(chain_id.to_string()) {
+        queue_message(&node_id, &ip, "sync-chain", chain_id, &format!("sync-chain:{}", chain_id), "");
+    }
+}
+
+// A provider that has just been sent the whole chain needs every attachment its records reference, except those of
+// records it can't read
+fn queue_chain_attachments(chain_id: &str, node_id: &str, ip: &str) {
+    let withheld = withheld_attachments(chain_id, node_id);
+    for hash in list_attachments(chain_id).into_iter().filter(|hash| !withheld.contains(hash)) {
+        queue_attachment(chain_id, &hash, node_id, ip);
+    }
+}
+
+fn queue_attachment(chain_id: &str, hash: &str, node_id: &str, ip: &str) {
+    queue_message(node_id, ip, "send-attachment", chain_id, &format!("send-attachment:{}:{}", chain_id, hash), hash);
+}
+
+pub fn pending_outbox() -> Result<Vec<PeerOutbox>, String> {
+    let mut peers: Vec<PeerOutbox> = vec![];
+    for entry in fetch_outbox().map_err(|err| err.to_string())? {
+        match peers.iter_mut().find(|peer| peer.node_id == entry.node_id) {
+            Some(peer) => peer.messages.push(entry),
+            None => peers.push(PeerOutbox{ node_id: entry.node_id.clone(), ip: entry.ip.clone(), messages: vec![entry] })
         }
+    }
+    Ok(peers)
+}
 
-    } else {
-        None
+// Every provider we share an active chain with, once each
+fn known_peers() -> Vec<(String, String)> {
+    let mut peers: Vec<(String, String)> = vec![];
+    for chain_id in fetch_chain_ids().unwrap_or_default() {
+        if !is_chain_active(chain_id.clone()).unwrap_or(false) {
+            continue;
+        }
+        for (node_id, ip) in get_active_providers(chain_id) {
+            if !node_id.is_empty() && !peers.iter().any(|(known, _)| *known == node_id) {
+                peers.push((node_id, ip));
+            }
+        }
     }
+    peers
 }
 
-fn request_remote(ip: String, request: &P2PRequest) -> P2PResponse {
-    let my_local_ip = local_ip().unwrap();
-    if my_local_ip.to_string() == ip {
-        return P2PResponse{ ok: true, data: Value::Null };
+// A newer version of an entry replaces one still waiting to go to the same peer. The peer an entry came from, and the
+// provider it describes, already have it.
+fn queue_directory_entries(entries: &[DirectoryEntry], except: &str) {
+    for (node_id, ip) in known_peers().into_iter().filter(|(node_id, _)| node_id != except) {
+        for entry in entries.iter().filter(|entry| entry.node_id != node_id) {
+            queue_directory_entry(&node_id, &ip, entry);
+        }
     }
+}
 
-    let tls_attempt = connect_to_host(ip.clone());
-    if let Some(mut tls) = tls_attempt {
-        let serialized_request = to_string(&request).unwrap();
-    
-        let _ = tls.write_all(serialized_request.as_bytes());
-        tls.flush().unwrap();
-    
-        let mut buf = [0; 32896];
-        let response = tls.read(&mut buf);
-        match response {
-            Ok(_) => P2PResponse{ ok: true, data: Value::Null },
-            Err(_) => P2PResponse{ok: false, data: Value::Null},
+fn queue_directory_entry(node_id: &str, ip: &str, entry: &DirectoryEntry) {
+    let mut parameters = Map::new();
+    parameters.insert("entries".to_string(), to_value(vec![entry]).unwrap());
+    let directory_entries_message = P2PRequest{
+        action: "directory-entries".to_string(),
+        parameters
+    };
+    queue_request(node_id, ip, "", &format!("directory:{}", entry.node_id), &directory_entries_message);
+}
+
+// Ask every peer for its directory, to pick up entries published while we were offline
+pub async fn sync_directory() {
+    for_each_provider(known_peers(), |node_id, ip| async move {
+        let request = P2PRequest{
+            action: "get-directory".to_string(),
+            parameters: Map::new()
+        };
+        let entries = match request_remote(&node_id, ip.clone(), &request).await {
+            Ok(data) => data.get("entries").cloned().and_then(|entries| from_value::<Vec<DirectoryEntry>>(entries).ok()).unwrap_or_default(),
+            Err(err) => {
+                eprintln!("Unable to fetch the directory from {}: {}", ip, err);
+                return;
+            }
+        };
+        for entry in entries {
+            if let Err(err) = store_entry(entry.clone()) {
+                eprintln!("Refused directory entry for {} from {}: {}", entry.name, ip, err);
+            }
         }
-    } else {
-        P2PResponse{ok: false, data: Value::Null}
+    }).await;
+}
+
+// Runs for the life of the daemon. Each peer's messages are delivered in the order they were queued, and a peer whose
+// oldest message is waiting to be retried is skipped, so later messages never overtake it. Messages that failed for
+// good stay in the outbox to be reported, but are no longer sent. Every peer is delivered to on its own task, so a
+// slow peer doesn't hold up the others.
+async fn deliver_outbox() {
+    // Peers with a delivery under way, whose messages are left to it
+    let delivering: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        let now = Utc::now().timestamp();
+        let mut peers: Vec<Vec<OutboxEntry>> = vec![];
+        for entry in fetch_outbox().unwrap_or_default().into_iter().filter(|entry| !entry.failed) {
+            match peers.iter_mut().find(|entries| entries[0].node_id == entry.node_id) {
+                Some(entries) => entries.push(entry),
+                None => peers.push(vec![entry])
+            }
+        }
+
+        for entries in peers.into_iter().filter(|entries| entries[0].next_attempt <= now) {
+            let node_id = entries[0].node_id.clone();
+            if !delivering.lock().unwrap().insert(node_id.clone()) {
+                continue;
+            }
+            let delivering = delivering.clone();
+            tokio::spawn(async move {
+                deliver_to_peer(entries).await;
+                delivering.lock().unwrap().remove(&node_id);
+                // Messages queued for the peer while it was being delivered to are picked up straight away
+                OUTBOX_READY.notify_one();
+            });
+        }
+
+        // Wake for newly queued messages, or to retry
+        let _ = timeout(OUTBOX_POLL_INTERVAL, OUTBOX_READY.notified()).await;
     }
 }
 
-fn add_remote_provider(ip: String, chain_id: String) {
-    let shared_key = get_shared_key(chain_id.clone()).unwrap();
+async fn deliver_to_peer(entries: Vec<OutboxEntry>) {
+    for entry in entries {
+        match deliver(&entry).await {
+            Ok(()) => {
+                let _ = delete_outbox_entry(entry.id);
+            },
+            Err(error) => {
+                eprintln!("Unable to deliver {} for chain {} to {}: {}", entry.action, entry.chain_id, entry.ip, error);
+                let attempts = entry.attempts + 1;
+                if !error.code.is_retryable() {
+                    let _ = fail_outbox_entry(entry.id, attempts, error.code.as_str(), &error.message);
+                    continue;
+                }
+                let _ = reschedule_outbox_entry(entry.id, attempts, Utc::now().timestamp() + retry_delay(attempts), error.code.as_str(), &error.message);
+                return;
+            }
+        }
+    }
+}
+
+// A message counts as delivered once the peer has applied it
+async fn deliver(entry: &OutboxEntry) -> Result<(), P2PError> {
+    match entry.action.as_str() {
+        "sync-chain" => sync_chain(entry.chain_id.clone(), &entry.node_id, entry.ip.clone()).await,
+        "send-attachment" => send_attachment(&entry.chain_id, &entry.message, &entry.node_id, &entry.ip).await,
+        _ => {
+            let request: P2PRequest = from_str(&entry.message).map_err(|err| p2p_error(P2PErrorCode::InvalidRequest, err.to_string()))?;
+            request_remote(&entry.node_id, entry.ip.clone(), &request).await.map(|_| ())
+        }
+    }
+}
+
+// Doubles with every failed attempt, up to OUTBOX_MAX_RETRY_DELAY
+fn retry_delay(attempts: i64) -> i64 {
+    let doublings = (attempts - 1).clamp(0, 16) as u32;
+    (OUTBOX_RETRY_DELAY * 2_i64.pow(doublings)).min(OUTBOX_MAX_RETRY_DELAY)
+}
+
+fn add_remote_provider(ip: String, public_key: String, chain_id: String) {
+    // The new provider gets the key of every epoch, since older blocks stay encrypted under the key they were written with.
+    // Each key is wrapped to the provider's public key, so only they can read it.
+    let mut shared_keys: Vec<Value> = vec![];
+    for (epoch, key) in fetch_epoch_keys(chain_id.clone()).unwrap_or_default() {
+        match wrap_key(&key, &public_key) {
+            Ok(wrapped) => shared_keys.push(json!({"epoch": epoch, "key": wrapped})),
+            Err(err) => {
+                eprintln!("Unable to share keys of chain {} with {}: {}", chain_id, ip, err);
+                return;
+            }
+        }
+    }
     let mut parameters = Map::new();
     parameters.insert("chain_id".to_string(), to_value(chain_id.clone()).unwrap());
-    parameters.insert("shared_key".to_string(), to_value(shared_key).unwrap());
+    parameters.insert("shared_keys".to_string(), Value::Array(shared_keys));
     let share_key_message = P2PRequest{
         action: "add-provider".to_string(),
         parameters
     };
-    let _ = request_remote(ip.clone(), &share_key_message);
+    let node_id = key_id(&public_key);
+    queue_request(&node_id, &ip, &chain_id, &format!("add-provider:{}", chain_id), &share_key_message);
 
-    // Send all the blocks to all providers
-    let providers = get_active_providers(chain_id.clone());
-    for provider in providers {
-        send_chain_update(chain_id.clone(), provider.1);
+    // The chain's attachments are queued once the provider holds the chain, since it refuses them before
+    queue_chain_sync(&chain_id);
+
+    // Our directory entry was only sent to the peers we had when it was published
+    if let Ok(Some(entry)) = fetch_directory_entry(&my_node_id()) {
+        queue_directory_entry(&node_id, &ip, &entry);
     }
 }
 
-fn remove_remote_provider(ip: String, chain_id: String) {
+fn remove_remote_provider(node_id: String, ip: String, chain_id: String) {
     let mut parameters = Map::new();
     parameters.insert("chain_id".to_string(), to_value(chain_id.clone()).unwrap());
     
@@ -236,126 +665,613 @@ fn remove_remote_provider(ip: String, chain_id: String) {
         action: "access_revoked".to_string(),
         parameters
     };
-    let _ = request_remote(ip.clone(), &access_revoked_message);
+    queue_request(&node_id, &ip, &chain_id, &format!("access_revoked:{}", chain_id), &access_revoked_message);
+
+    queue_chain_sync(&chain_id);
+}
+
+// Blocks may have been added while we were offline, so every active chain's heads are compared with its providers'.
+// Chains we are behind on are pulled, and providers that are behind us are sent what they missed.
+pub async fn catch_up_chains() {
+    let chain_ids: Vec<String> = fetch_chain_ids().unwrap_or_default().into_iter()
+        .filter(|chain_id| is_chain_active(chain_id.clone()).unwrap_or(false) && check_integrity(chain_id).is_ok())
+        .collect();
+    {
+        let mut progress = CATCH_UP_PROGRESS.lock().unwrap();
+        progress.running = true;
+        progress.chains = chain_ids.iter().map(|chain_id| {
+            let (length, _) = chain_head(chain_id);
+            ChainCatchUp{ chain_id: chain_id.clone(), status: "waiting".to_string(), start_length: length, length }
+        }).collect();
+    }
+
+    for (index, chain_id) in chain_ids.into_iter().enumerate() {
+        set_catch_up_status(index, "syncing");
+        let providers = get_active_providers(chain_id.clone());
+        for_each_provider(providers, move |node_id, ip| {
+            let chain_id = chain_id.clone();
+            async move {
+                if let Err(err) = sync_chain(chain_id.clone(), &node_id, ip).await {
+                    eprintln!("Unable to catch up on chain {}: {}", chain_id, err);
+                }
+            }
+        }).await;
+        set_catch_up_status(index, "done");
+    }
+    CATCH_UP_PROGRESS.lock().unwrap().running = false;
+}
 
-    // Send all the blocks to all providers
-    let providers = get_active_providers(chain_id.clone());
-    for provider in providers {
-        send_chain_update(chain_id.clone(), provider.1);
+fn set_catch_up_status(index: usize, status: &str) {
+    let mut progress = CATCH_UP_PROGRESS.lock().unwrap();
+    if let Some(chain) = progress.chains.get_mut(index) {
+        chain.status = status.to_string();
+        chain.length = chain_head(&chain.chain_id).0;
     }
 }
 
+pub fn catch_up_progress() -> CatchUpProgress {
+    CATCH_UP_PROGRESS.lock().unwrap().clone()
+}
+
 fn send_new_shared_key(chain_id: String){
-    let mut parameters = Map::new();
     let shared_key = get_shared_key(chain_id.clone()).unwrap();
-    parameters.insert("chain_id".to_string(), to_value(chain_id.clone()).unwrap());
-    parameters.insert("shared_key".to_string(), to_value(shared_key).unwrap());
-    let providers = get_active_providers(chain_id);
-    let update_shared_key_message = P2PRequest{
-        action: "update-shared-key".to_string(),
-        parameters
-    };
+    let epoch = get_current_epoch(chain_id.clone()).unwrap();
 
-    for provider in providers {
-        request_remote(provider.1, &update_shared_key_message);
+    // Every remaining provider gets its own copy of the key, wrapped to its public key
+    let my_node_id = my_node_id();
+    for (ip, public_key) in fetch_provider_public_keys(chain_id.clone()).unwrap_or_default() {
+        let node_id = key_id(&public_key);
+        if node_id == my_node_id {
+            continue;
+        }
+        let wrapped = match wrap_key(&shared_key, &public_key) {
+            Ok(wrapped) => wrapped,
+            Err(err) => {
+                eprintln!("Unable to send new key of chain {} to {}: {}", chain_id, ip, err);
+                continue;
+            }
+        };
+        let mut parameters = Map::new();
+        parameters.insert("chain_id".to_string(), to_value(chain_id.clone()).unwrap());
+        parameters.insert("epoch".to_string(), to_value(epoch).unwrap());
+        parameters.insert("shared_key".to_string(), to_value(wrapped).unwrap());
+        let update_shared_key_message = P2PRequest{
+            action: "update-shared-key".to_string(),
+            parameters
+        };
+        queue_request(&node_id, &ip, &chain_id, &format!("update-shared-key:{}:{}", chain_id, epoch), &update_shared_key_message);
     }
 }
 
 fn add_record(parameters: Map<String, Value>) {
-    let providers = get_active_providers(parameters.get("chain_id").unwrap().as_str().unwrap().to_string());
+    let chain_id = parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
+    let attachment_hashes: Vec<String> = match parameters.get("attachments") {
+        Some(Value::Array(attachments)) => attachments.iter()
+            .filter_map(|attachment| attachment.get("hash").and_then(Value::as_str).map(str::to_string))
+            .collect(),
+        _ => vec![]
+    };
 
-    for provider in providers {
-        send_chain_update(parameters.get("chain_id").unwrap().as_str().unwrap().to_string(), provider.1);
+    // Sealed records name their readers; nobody else is sent the record's attachments
+    let readers: Option<Vec<String>> = parameters.get("readers").and_then(|readers| from_value(readers.clone()).ok());
+
+    // Each attachment is sent after syncing the chain, so the provider holds the record with its key first
+    for (node_id, ip) in get_active_providers(chain_id.clone()) {
+        if readers.as_ref().is_some_and(|readers| !readers.contains(&node_id)) {
+            continue;
+        }
+        for hash in &attachment_hashes {
+            queue_attachment(&chain_id, hash, &node_id, &ip);
+        }
     }
+    queue_chain_sync(&chain_id);
 }
 
-fn send_chain_update(chain_id: String, ip: String) {
+// Attachments are sent still encrypted under their own key, one chunk per request, over one connection. The chain
+// is synced first, since the provider can only check the attachment with the key in the record referencing it.
+async fn send_attachment(chain_id: &str, hash: &str, node_id: &str, ip: &str) -> Result<(), P2PError> {
+    sync_chain(chain_id.to_string(), node_id, ip.to_string()).await?;
+    let sealed = read_encrypted_attachment(chain_id, hash).map_err(|err| p2p_error(P2PErrorCode::InvalidAttachment, err))?;
+    let mut tls = open_remote(node_id, ip).await?;
+    let total = sealed.len().div_ceil(ATTACHMENT_CHUNK_SIZE);
+
+    for (index, chunk) in sealed.chunks(ATTACHMENT_CHUNK_SIZE).enumerate() {
+        let mut parameters = Map::new();
+        parameters.insert("chain_id".to_string(), to_value(chain_id).unwrap());
+        parameters.insert("hash".to_string(), to_value(hash).unwrap());
+        parameters.insert("index".to_string(), to_value(index).unwrap());
+        parameters.insert("total".to_string(), to_value(total).unwrap());
+        parameters.insert("data".to_string(), to_value(chunk.to_hex()).unwrap());
+        let attachment_chunk_message = P2PRequest{
+            action: "attachment-chunk".to_string(),
+            parameters
+        };
+        accepted(send_request(&mut tls, ip, &attachment_chunk_message).await?)?;
+    }
+    let _ = tls.shutdown().await;
+    Ok(())
+}
+
+// Heads are compared first, so only the blocks the provider is missing are sent. A provider that is ahead of us is
+// asked for the blocks we lack instead.
+async fn sync_chain(chain_id: String, node_id: &str, ip: String) -> Result<(), P2PError> {
+    check_integrity(&chain_id)?;
+    let mut tls = open_remote(node_id, &ip).await?;
+    let (length, head_hash) = chain_head(&chain_id);
     let mut parameters = Map::new();
-    let blocks = fetch_all_blocks(chain_id).unwrap();
-    let json_blocks = to_value(blocks).unwrap();
-    parameters.insert("blocks".to_string(), json_blocks);
-    let update_chain_message = P2PRequest{
-        action: "update-chain".to_string(),
+    parameters.insert("chain_id".to_string(), to_value(&chain_id).unwrap());
+    parameters.insert("length".to_string(), to_value(length).unwrap());
+    parameters.insert("head_hash".to_string(), to_value(&head_hash).unwrap());
+    let chain_length_message = P2PRequest{
+        action: "chain-length".to_string(),
         parameters
     };
+    let data = accepted(send_request(&mut tls, &ip, &chain_length_message).await?)?;
+    let (their_length, their_head) = match (data.get("length").and_then(Value::as_i64), data.get("head_hash").and_then(Value::as_str)) {
+        (Some(length), Some(head_hash)) => (length, head_hash.to_string()),
+        _ => return Err(p2p_error(P2PErrorCode::InvalidResponse, format!("Malformed chain length for chain {} from {}", chain_id, ip)))
+    };
+
+    let result = if their_length < length {
+        match holds_head(&chain_id, their_length, &their_head) {
+            true if their_length == 0 => {
+                let result = push_blocks(&mut tls, &ip, fetch_blocks_from(chain_id.clone(), 0).unwrap_or_default()).await;
+                if result.is_ok() {
+                    queue_chain_attachments(&chain_id, node_id, &ip);
+                }
+                result
+            },
+            true => push_blocks(&mut tls, &ip, fetch_blocks_from(chain_id, their_length).unwrap_or_default()).await,
+            false => Err(p2p_error(P2PErrorCode::Diverged, format!("The copy of chain {} at {} has diverged from ours", chain_id, ip)))
+        }
+    } else if their_length > length {
+        pull_blocks(&mut tls, &ip, &chain_id).await
+    } else if their_head != head_hash {
+        Err(p2p_error(P2PErrorCode::Diverged, format!("The copy of chain {} at {} has diverged from ours", chain_id, ip)))
+    } else {
+        Ok(())
+    };
+    let _ = tls.shutdown().await;
+    result
+}
+
+// Blocks go in batches over one connection, so a chain's size isn't limited by the largest message
+async fn push_blocks(tls: &mut client::TlsStream<TcpStream>, ip: &str, blocks: Vec<Block>) -> Result<(), P2PError> {
+    for batch in batch_blocks(blocks) {
+        let mut parameters = Map::new();
+        parameters.insert("blocks".to_string(), Value::Array(batch));
+        let update_chain_message = P2PRequest{
+            action: "update-chain".to_string(),
+            parameters
+        };
+        accepted(send_request(tls, ip, &update_chain_message).await?)?;
+    }
+    Ok(())
+}
+
+// Ask a provider for the blocks after our head, a batch at a time, until we hold everything it has
+async fn pull_blocks(tls: &mut client::TlsStream<TcpStream>, ip: &str, chain_id: &str) -> Result<(), P2PError> {
+    loop {
+        let (length, head_hash) = chain_head(chain_id);
+        let mut parameters = Map::new();
+        parameters.insert("chain_id".to_string(), to_value(chain_id).unwrap());
+        parameters.insert("length".to_string(), to_value(length).unwrap());
+        parameters.insert("head_hash".to_string(), to_value(&head_hash).unwrap());
+        let request_chain_update_message = P2PRequest{
+            action: "request-chain-update".to_string(),
+            parameters
+        };
+        let data = match send_request(tls, ip, &request_chain_update_message).await? {
+            P2PResponse{ ok: true, data, .. } => data,
+            P2PResponse{ error: Some(error), .. } => return Err(error),
+            P2PResponse{ error: None, .. } => return Err(p2p_error(P2PErrorCode::InvalidResponse, format!("Provider at {} refused to send chain {}", ip, chain_id)))
+        };
+        let blocks: Vec<Block> = match data.get("blocks").map(|blocks| from_value(blocks.clone())) {
+            Some(Ok(blocks)) => blocks,
+            _ => return Err(p2p_error(P2PErrorCode::InvalidResponse, format!("Malformed blocks in chain update from {}", ip)))
+        };
+        // Only blocks of the chain we asked for; any others would skip the checks on who may update them
+        for block in blocks.into_iter().filter(|block| block.chain_id == chain_id) {
+            let block_id = block.id;
+            if let Err(err) = add_block(block) {
+                return Err(p2p_error(err.code, format!("Rejected block {} for chain {} from {}: {}", block_id, chain_id, ip, err.message)));
+            }
+        }
+
+        // Stop once level with the provider, or when its blocks didn't extend our chain
+        let their_length = data.get("length").and_then(Value::as_i64).unwrap_or(0);
+        let (new_length, _) = chain_head(chain_id);
+        if new_length <= length || new_length >= their_length {
+            return Ok(());
+        }
+    }
+}
+
+// Split blocks into batches of about BLOCK_BATCH_SIZE bytes each; a larger block goes in a batch of its own
+fn batch_blocks(blocks: Vec<Block>) -> Vec<Vec<Value>> {
+    let mut batches: Vec<Vec<Value>> = vec![];
+    let mut batch_size = 0;
+    for block in blocks {
+        let block = to_value(block).unwrap();
+        let size = to_string(&block).unwrap().len();
+        match batches.last_mut() {
+            Some(batch) if batch_size + size <= BLOCK_BATCH_SIZE => {
+                batch.push(block);
+                batch_size += size;
+            },
+            _ => {
+                batches.push(vec![block]);
+                batch_size = size;
+            }
+        }
+    }
+    batches
+}
 
-    let _ = request_remote(ip.clone(), &update_chain_message);
+// A chain's length and the hash of its last block, or 0 and an empty hash for a chain we don't hold
+fn chain_head(chain_id: &str) -> (i64, String) {
+    match fetch_last_block(chain_id.to_string()) {
+        Ok(block) => (block.id + 1, block.hash),
+        Err(_) => (0, String::new())
+    }
+}
+
+// Whether a peer's copy of a chain is a prefix of ours, i.e. we hold the block at its head
+fn holds_head(chain_id: &str, length: i64, head_hash: &str) -> bool {
+    length == 0 || fetch_block_hash(chain_id.to_string(), length - 1).map(|hash| hash == head_hash).unwrap_or(false)
 }
 
 // --------- INCOMING REQUEST HANDLING ------------ //
 
-fn handle_request(request: P2PRequest) -> P2PResponse {
-    match request.action.as_str() {
-        "add-provider" => {
-            add_provider_from_remote(request)
-        },
-        "update-chain" => {
-            update_chain_from_remote(request)
-        },
-        "update-shared-key" => {
-            update_shared_key(request)
-        },
-        "access_revoked" => {
-            deactivate_chain(request)
+fn handle_request(request: P2PRequest, session: &mut Session) -> P2PResponse {
+    let peer = session.peer.clone();
+    let action = request.action.clone();
+    match route_request(request, session) {
+        Ok(data) => P2PResponse{ ok: true, data, error: None },
+        Err((error, data)) => {
+            eprintln!("Refused {} from {}: {}", action, peer, error);
+            P2PResponse{ ok: false, data, error: Some(error) }
         }
+    }
+}
+
+// A refused request may still return data, so errors come with it
+fn route_request(request: P2PRequest, session: &mut Session) -> Result<Value, (P2PError, Value)> {
+    let peer = session.peer.clone();
+    // Blocks name their own chains, so chain updates are authorized per chain as they are ingested
+    match request.action.as_str() {
+        "get-public-key" => return public_key_response().map_err(|error| (error, Value::Null)),
+        "update-chain" => return update_chain_from_remote(request, session),
+        // Directory entries aren't about a chain, and are checked against their own signatures instead
+        "directory-entries" => return directory_entries_from_remote(request, &peer).map_err(|error| (error, Value::Null)),
+        "get-directory" => return directory_response().map_err(|error| (error, Value::Null)),
         _ => {}
     }
-    P2PResponse{ ok: false, data: Value::Null }
+
+    let chain_id = match request.parameters.get("chain_id").and_then(Value::as_str) {
+        Some(chain_id) => chain_id.to_string(),
+        None => return Err((p2p_error(P2PErrorCode::InvalidRequest, "no chain id".to_string()), Value::Null))
+    };
+    authorize(&request.action, &chain_id, &peer).map_err(|error| (error, Value::Null))?;
+    if matches!(request.action.as_str(), "attachment-chunk" | "chain-length" | "request-chain-update") {
+        check_integrity(&chain_id).map_err(|error| (error, Value::Null))?;
+    }
+
+    match request.action.as_str() {
+        "add-provider" => add_provider_from_remote(request, &chain_id, &peer),
+        "update-shared-key" => update_shared_key(request, &chain_id),
+        "access_revoked" => deactivate_chain(&chain_id),
+        "attachment-chunk" => receive_attachment_chunk(request, &chain_id),
+        "chain-length" => Ok(chain_length_response(&chain_id)),
+        "request-chain-update" => chain_update_response(request, &chain_id),
+        _ => Err(p2p_error(P2PErrorCode::UnknownAction, format!("unknown action {}", request.action)))
+    }.map_err(|error| (error, Value::Null))
+}
+
+pub fn p2p_error(code: P2PErrorCode, message: String) -> P2PError {
+    P2PError{ code, message }
 }
 
-fn add_provider_from_remote(request: P2PRequest){
-    let chain_id = request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
-    let shared_key_value = request.parameters.get("shared_key").unwrap();
+// A chain that failed the integrity check at startup is neither sent to other providers nor updated from them, so
+// a damaged copy doesn't spread and isn't built on until it is repaired
+fn check_integrity(chain_id: &str) -> Result<(), P2PError> {
+    match is_chain_integrity_failed(chain_id) {
+        Ok(true) => Err(p2p_error(P2PErrorCode::IntegrityFailed, format!("chain {} failed its integrity check", chain_id))),
+        _ => Ok(())
+    }
+}
 
-    // Convert the shared_key_value to a Vec<u8>
-    let shared_key:Vec<u8> = match shared_key_value {
-        Value::Array(array) => array.iter().map(|v| v.as_u64().unwrap() as u8).collect(),
-        _ => panic!("shared_key field is not an array"),
+// Requests about a chain we hold must come from a provider on it. Sharing and replacing keys and revoking our
+// access also take an administrator or owner. A chain we don't hold yet is being shared with us, so its keys and
+// blocks are accepted from any authenticated node. The keys are only used once the blocks show their sender could
+// share them, and the blocks are still checked against their signatures and the chain's roles when they are added.
+fn authorize(action: &str, chain_id: &str, peer: &str) -> Result<(), P2PError> {
+    let required = match action {
+        "add-provider" | "update-shared-key" | "access_revoked" => ProviderRole::Administrator,
+        "update-chain" | "attachment-chunk" | "chain-length" | "request-chain-update" => ProviderRole::Reader,
+        _ => return Err(p2p_error(P2PErrorCode::UnknownAction, format!("unknown action {}", action)))
     };
+    if !chain_exists(chain_id.to_string()).unwrap_or(false) {
+        return match action {
+            "update-shared-key" | "access_revoked" | "request-chain-update" | "attachment-chunk" => Err(p2p_error(P2PErrorCode::UnknownChain, format!("chain {} is not held here", chain_id))),
+            _ => Ok(())
+        };
+    }
+    match node_role(chain_id, peer) {
+        Some(role) if role >= required => Ok(()),
+        Some(role) => Err(p2p_error(P2PErrorCode::Unauthorized, format!("{} cannot {} on chain {}", role.as_str(), action, chain_id))),
+        None => Err(p2p_error(P2PErrorCode::Unauthorized, format!("not a provider on chain {}", chain_id)))
+    }
+}
 
-    if chain_exists(chain_id.clone()).unwrap() {
-        let old_key = get_shared_key(chain_id.clone()).unwrap();
-        for block in fetch_all_blocks(chain_id.clone()).unwrap(){
-            let new_block = reencrypt_block(&block, &old_key, &shared_key).unwrap();
-            let _ = update_block(&new_block);
-        }
+// Report our head so the sender can tell which blocks we are missing, or which it is
+fn chain_length_response(chain_id: &str) -> Value {
+    let (length, head_hash) = chain_head(chain_id);
+    json!({"chain_id": chain_id, "length": length, "head_hash": head_hash})
+}
+
+// The blocks after the requester's head, one batch at a time; it asks again until it has caught up
+fn chain_update_response(request: P2PRequest, chain_id: &str) -> Result<Value, P2PError> {
+    let length = request.parameters.get("length").and_then(Value::as_i64).unwrap_or(0);
+    let head_hash = request.parameters.get("head_hash").and_then(Value::as_str).unwrap_or_default();
+    if !holds_head(chain_id, length, head_hash) {
+        return Err(p2p_error(P2PErrorCode::Diverged, format!("chain {} has diverged from the requester's copy", chain_id)));
     }
+    let blocks = fetch_blocks_from(chain_id.to_string(), length).unwrap_or_default();
+    let batch = batch_blocks(blocks).into_iter().next().unwrap_or_default();
+    let (our_length, our_head) = chain_head(chain_id);
+    Ok(json!({"chain_id": chain_id, "blocks": batch, "length": our_length, "head_hash": our_head}))
+}
 
-    insert_new_shared_key(&shared_key, chain_id).unwrap();
+fn directory_response() -> Result<Value, P2PError> {
+    match fetch_directory() {
+        Ok(entries) => Ok(json!({"entries": entries})),
+        Err(err) => Err(p2p_error(P2PErrorCode::Internal, err.to_string()))
+    }
 }
 
-fn update_chain_from_remote(request: P2PRequest) {
-    let json_blocks_value = request.parameters.get("blocks").unwrap();
+// Entries that were new to us are passed on to our other peers, so a listing spreads to every node connected to its
+// provider. Replies with how many were stored and how many were refused.
+fn directory_entries_from_remote(request: P2PRequest, peer: &str) -> Result<Value, P2PError> {
+    let entries: Vec<DirectoryEntry> = match request.parameters.get("entries").map(|entries| from_value(entries.clone())) {
+        Some(Ok(entries)) => entries,
+        _ => return Err(p2p_error(P2PErrorCode::InvalidRequest, "malformed directory entries".to_string()))
+    };
+    if entries.len() > MAX_ENTRIES_PER_MESSAGE {
+        return Err(p2p_error(P2PErrorCode::InvalidRequest, format!("more than {} directory entries", MAX_ENTRIES_PER_MESSAGE)));
+    }
+    let mut stored: Vec<DirectoryEntry> = vec![];
+    let mut refused = 0;
+    for entry in entries {
+        match store_entry(entry.clone()) {
+            Ok(Some(entry)) => stored.push(entry),
+            Ok(None) => {},
+            Err(err) => {
+                eprintln!("Refused directory entry for {} from {}: {}", entry.name, peer, err);
+                refused += 1;
+            }
+        }
+    }
+    queue_directory_entries(&stored, peer);
+    Ok(json!({"stored": stored.len(), "refused": refused}))
+}
 
-    let blocks: Vec<Block> = from_value(json_blocks_value.clone()).unwrap();
-    for block in blocks {
-        add_block(block);
+fn public_key_response() -> Result<Value, P2PError> {
+    match get_key_pair() {
+        Ok(Some(key_pair)) => Ok(Value::String(key_pair.public_key)),
+        _ => Err(p2p_error(P2PErrorCode::Internal, "no key pair".to_string()))
     }
 }
 
-fn update_shared_key(request: P2PRequest) {
-    let chain_id = request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
-    let new_key_value = request.parameters.get("shared_key").unwrap();
-    let new_key:Vec<u8> = match new_key_value {
-        Value::Array(array) => array.iter().map(|v| v.as_u64().unwrap() as u8).collect(),
-        _ => panic!("shared_key field is not an array"),
+// Replies with the epochs whose keys were stored. Keys for a chain we don't hold yet could come from any node, so they
+// are kept by sender until the chain's blocks show whether that node could share them.
+fn add_provider_from_remote(request: P2PRequest, chain_id: &str, peer: &str) -> Result<Value, P2PError> {
+    let shared_keys = match request.parameters.get("shared_keys") {
+        Some(Value::Array(shared_keys)) => shared_keys,
+        _ => return Err(p2p_error(P2PErrorCode::InvalidRequest, "no shared keys".to_string()))
     };
 
-    let old_key_vec = get_shared_key(chain_id.clone()).unwrap();
-    let old_key = old_key_vec.as_slice();
+    let held = chain_exists(chain_id.to_string()).unwrap_or(false);
+    if !held && count_pending_key_chains(peer, chain_id).unwrap_or(0) >= MAX_PENDING_KEY_CHAINS {
+        return Err(p2p_error(P2PErrorCode::InvalidRequest, format!("keys for over {} chains not held here", MAX_PENDING_KEY_CHAINS)));
+    }
+
+    let mut epochs: Vec<i64> = vec![];
+    for shared_key in shared_keys {
+        let epoch = shared_key.get("epoch").and_then(Value::as_i64).unwrap_or(0);
+        let key = match shared_key.get("key").and_then(unwrap_shared_key) {
+            Some(key) => key,
+            None => return Err(p2p_error(P2PErrorCode::InvalidKey, format!("unable to unwrap key for epoch {} of chain {}", epoch, chain_id)))
+        };
+        if held {
+            store_epoch_key(&key, chain_id, epoch)?;
+        } else {
+            insert_pending_key(chain_id, peer, epoch, &key).map_err(|err| p2p_error(P2PErrorCode::Internal, err.to_string()))?;
+        }
+        epochs.push(epoch);
+    }
+    Ok(json!({"chain_id": chain_id, "epochs": epochs}))
+}
+
+// Replies with how many blocks were added or held back. A rejected block stops the update, since the blocks after it can't follow
+// on from our chain; the error still says how many were added before it.
+fn update_chain_from_remote(request: P2PRequest, session: &mut Session) -> Result<Value, (P2PError, Value)> {
+    let peer = session.peer.clone();
+    let json_blocks_value = match request.parameters.get("blocks") {
+        Some(value) => value,
+        None => return Err((p2p_error(P2PErrorCode::InvalidRequest, "no blocks".to_string()), Value::Null))
+    };
 
-    let blocks = fetch_all_blocks(chain_id.clone()).unwrap();
+    let blocks: Vec<Block> = match from_value(json_blocks_value.clone()) {
+        Ok(blocks) => blocks,
+        Err(err) => return Err((p2p_error(P2PErrorCode::InvalidRequest, format!("malformed blocks: {}", err)), Value::Null))
+    };
+    // Decided once per chain and connection before any block is added, so a sender added partway through the
+    // update is judged by the chain as we held it
+    let mut accepted = 0;
+    let mut new_chains: Vec<String> = vec![];
     for block in blocks {
-        let new_block_option = reencrypt_block(&block, old_key, &new_key);
-        if let Some(new_block) = new_block_option {
-            let _ = update_block(&new_block);
+        let block_id = block.id;
+        let chain_id = block.chain_id.clone();
+        // A chain we don't hold yet is only stored once its blocks show we were added to it, which may take several batches
+        if !chain_exists(chain_id.clone()).unwrap_or(false) {
+            session.pending_size += block.data.len();
+            if session.pending_size > MAX_PENDING_CHAIN_SIZE {
+                return Err((p2p_error(P2PErrorCode::InvalidRequest, format!("over {} bytes of chains not held here", MAX_PENDING_CHAIN_SIZE)), json!({"accepted": accepted})));
+            }
+            session.pending.entry(chain_id.clone()).or_default().push(block);
+            if !new_chains.contains(&chain_id) {
+                new_chains.push(chain_id);
+            }
+            accepted += 1;
+            continue;
         }
+        let authorized = session.authorized.entry(chain_id.clone()).or_insert_with(|| authorize("update-chain", &chain_id, &peer).is_ok());
+        let result = match *authorized {
+            true => check_integrity(&chain_id).and_then(|_| add_block(block).map_err(|err| p2p_error(err.code, format!("block {} of chain {}: {}", block_id, chain_id, err.message)))),
+            false => authorize("update-chain", &chain_id, &peer)
+        };
+        if let Err(error) = result {
+            return Err((error, json!({"accepted": accepted})));
+        }
+        accepted += 1;
     }
-    
-    let _ = insert_new_shared_key(&new_key, chain_id);
+    for chain_id in new_chains {
+        let result = add_new_chain(&session.pending[&chain_id]);
+        if !matches!(result, Ok(false)) {
+            let blocks = session.pending.remove(&chain_id).unwrap_or_default();
+            session.pending_size -= blocks.iter().map(|block| block.data.len()).sum::<usize>();
+        }
+        if let Err(error) = result {
+            return Err((error, json!({"accepted": accepted})));
+        }
+    }
+    Ok(json!({"accepted": accepted}))
 }
 
-fn deactivate_chain(request: P2PRequest) {
-    let chain_id = request.parameters.get("chain_id").unwrap().as_str().unwrap().to_string();
-    let _ = set_chain_active(chain_id, false);
-}
\ No newline at end of file
+// A provider was removed, so blocks from here on use a new epoch's key. Blocks we already hold keep theirs.
+fn update_shared_key(request: P2PRequest, chain_id: &str) -> Result<Value, P2PError> {
+    let epoch = request.parameters.get("epoch").and_then(Value::as_i64).unwrap_or(0);
+    let new_key = match request.parameters.get("shared_key").and_then(unwrap_shared_key) {
+        Some(key) => key,
+        None => return Err(p2p_error(P2PErrorCode::InvalidKey, format!("unable to unwrap new key for chain {}", chain_id)))
+    };
+    store_epoch_key(&new_key, chain_id, epoch)?;
+    Ok(json!({"chain_id": chain_id, "epoch": epoch}))
+}
+
+// An epoch's key never changes once we hold it, so a different key for it is refused rather than replacing it
+fn store_epoch_key(key: &[u8], chain_id: &str, epoch: i64) -> Result<(), P2PError> {
+    match get_epoch_key(chain_id.to_string(), epoch) {
+        Ok(existing) if existing != key => Err(p2p_error(P2PErrorCode::StaleKey, format!("a different key is held for epoch {} of chain {}", epoch, chain_id))),
+        _ => insert_shared_key(key, chain_id.to_string(), epoch).map_err(|err| p2p_error(P2PErrorCode::Internal, err.to_string()))
+    }
+}
+
+// Chain keys arrive wrapped to our public key with RSA-OAEP
+fn unwrap_shared_key(value: &Value) -> Option<Vec<u8>> {
+    let key_pair = get_key_pair().ok()??;
+    unwrap_key(value.as_str()?, &key_pair.private_key)
+}
+
+fn deactivate_chain(chain_id: &str) -> Result<Value, P2PError> {
+    set_chain_active(chain_id.to_string(), false).map_err(|err| p2p_error(P2PErrorCode::Internal, err.to_string()))?;
+    Ok(Value::Null)
+}
+
+// Replies with whether the attachment is complete
+fn receive_attachment_chunk(request: P2PRequest, chain_id: &str) -> Result<Value, P2PError> {
+    let parameters = &request.parameters;
+    let hash = match parameters.get("hash").and_then(Value::as_str) {
+        Some(hash) => hash,
+        None => return Err(p2p_error(P2PErrorCode::InvalidRequest, "no attachment hash".to_string()))
+    };
+    let (index, total) = match (parameters.get("index").and_then(Value::as_u64), parameters.get("total").and_then(Value::as_u64)) {
+        (Some(index), Some(total)) if index < total => (index as usize, total as usize),
+        _ => return Err(p2p_error(P2PErrorCode::InvalidRequest, "invalid chunk index".to_string()))
+    };
+    let bytes = match parameters.get("data").and_then(Value::as_str).map(|data| data.from_hex()) {
+        Some(Ok(bytes)) => bytes,
+        _ => return Err(p2p_error(P2PErrorCode::InvalidRequest, "invalid chunk data".to_string()))
+    };
+    // The key comes from a record referencing the attachment, which the sender syncs before sending it
+    let keys = attachment_keys(chain_id, hash);
+    if keys.is_empty() {
+        return Err(p2p_error(P2PErrorCode::InvalidAttachment, format!("no record we can read references attachment {}", hash)));
+    }
+
+    match write_attachment_chunk(chain_id, hash, index, total, &bytes, &keys) {
+        Ok(complete) => Ok(json!({"hash": hash, "index": index, "complete": complete})),
+        Err(err) => Err(p2p_error(P2PErrorCode::InvalidAttachment, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(length: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = length.to_be_bytes().to_vec();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn read_frame_returns_one_message_at_a_time() {
+        let mut bytes = frame(5, b"first");
+        bytes.extend(frame(6, b"second"));
+        let mut stream = bytes.as_slice();
+        assert_eq!(read_frame(&mut stream).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut stream).await.unwrap(), Some(b"second".to_vec()));
+        assert_eq!(read_frame(&mut stream).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_frame_refuses_messages_over_the_limit() {
+        let bytes = frame(DEFAULT_MAX_MESSAGE_SIZE as u32 + 1, b"");
+        let err = read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let bytes = frame(DEFAULT_MAX_MESSAGE_SIZE as u32, b"");
+        assert_eq!(read_frame(&mut bytes.as_slice()).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_frame_refuses_truncated_messages() {
+        let bytes = frame(10, b"short");
+        assert_eq!(read_frame(&mut bytes.as_slice()).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        let bytes = [0u8, 0];
+        assert_eq!(read_frame(&mut bytes.as_slice()).await.unwrap(), None);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        assert_eq!(retry_delay(0), OUTBOX_RETRY_DELAY);
+        assert_eq!(retry_delay(1), OUTBOX_RETRY_DELAY);
+        assert_eq!(retry_delay(2), OUTBOX_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(4), OUTBOX_RETRY_DELAY * 8);
+        assert_eq!(retry_delay(20), OUTBOX_MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(i64::MAX), OUTBOX_MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn only_applied_responses_are_accepted() {
+        let applied = P2PResponse{ ok: true, data: json!({"accepted": 1}), error: None };
+        assert_eq!(accepted(applied).unwrap(), json!({"accepted": 1}));
+
+        let refused = P2PResponse{ ok: false, data: Value::Null, error: Some(p2p_error(P2PErrorCode::Diverged, "split".to_string())) };
+        assert_eq!(accepted(refused).unwrap_err().code, P2PErrorCode::Diverged);
+
+        let unexplained = accepted(P2PResponse{ ok: false, data: Value::Null, error: None }).unwrap_err();
+        assert_eq!(unexplained.code, P2PErrorCode::InvalidResponse);
+        assert!(unexplained.code.is_retryable());
+    }
+
+    #[test]
+    fn unknown_error_codes_decode_as_unknown() {
+        let error: P2PError = from_value(json!({"code": "something_new", "message": "?"})).unwrap();
+        assert_eq!(error.code, P2PErrorCode::Unknown);
+        assert!(!P2PErrorCode::Diverged.is_retryable());
+        assert!(!P2PErrorCode::IntegrityFailed.is_retryable());
+    }
+}
@@ -0,0 +1,584 @@
+use std::net::IpAddr;
+use openssl::pkey::PKey;
+use rustc_serialize::hex::FromHex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_value, Map, Value};
+use crate::attachment::is_hash;
+
+// Typed block payload. Serialized as {"action": "...", "fields": {...}}, the same shape blocks had
+// before payloads were typed, so older blocks still decode and keep their data hashes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", content = "fields", rename_all = "kebab-case")]
+pub enum BlockData {
+    Genesis(GenesisFields),
+    AddProvider(AddProviderFields),
+    RemoveProvider(RemoveProviderFields),
+    UpdateProviderAddress(UpdateProviderAddressFields),
+    AddRecord(AddRecordFields),
+    AmendRecord(AmendRecordFields),
+    RetractRecord(RetractRecordFields),
+    ShareRecords(ShareRecordsFields),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisFields {
+    pub first_name: String,
+    pub last_name: String,
+    pub date_of_birth: String,
+}
+
+// The public key identifies the provider as the signer of the blocks they append, and its hash is the
+// provider's node id. ip is only the address the provider could be reached at when it was added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddProviderFields {
+    pub name: String,
+    pub ip: String,
+    #[serde(default)]
+    pub role: ProviderRole,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub public_key: String,
+}
+
+// Ordered from least to most privileged. Providers added before roles existed decode as contributors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderRole {
+    Reader,
+    #[default]
+    Contributor,
+    Administrator,
+    Owner,
+}
+
+impl ProviderRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProviderRole::Reader => "reader",
+            ProviderRole::Contributor => "contributor",
+            ProviderRole::Administrator => "administrator",
+            ProviderRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ProviderRole> {
+        from_value(Value::String(value.to_string())).ok()
+    }
+
+    // Readers can't append anything, contributors can append records, administrators can also share
+    // records and add and remove providers other than owners, and owners can do everything
+    pub fn can_append(&self, data: &BlockData, target_role: ProviderRole) -> bool {
+        match data {
+            BlockData::Genesis(_) => false,
+            BlockData::AddRecord(_) | BlockData::AmendRecord(_) | BlockData::RetractRecord(_) => *self >= ProviderRole::Contributor,
+            BlockData::ShareRecords(_) => *self >= ProviderRole::Administrator,
+            BlockData::AddProvider(_) | BlockData::RemoveProvider(_) | BlockData::UpdateProviderAddress(_) => {
+                *self == ProviderRole::Owner || (*self == ProviderRole::Administrator && target_role != ProviderRole::Owner)
+            },
+        }
+    }
+}
+
+// Providers are removed by node id. Blocks written before node ids existed name the provider by ip instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveProviderFields {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub node_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub ip: String,
+}
+
+// Records the address a provider can now be reached at, e.g. after its DHCP lease changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateProviderAddressFields {
+    pub node_id: String,
+    pub ip: String,
+}
+
+// Records without details are free-text notes, which is all records were before structured kinds.
+// Records written with their own data key keep their content in sealed, and the content fields are
+// only filled in locally once the sealed content has been opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddRecordFields {
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<RecordDetails>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentReference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedRecord>,
+}
+
+// Replaces the subject, text, details and attachments of the add-record block with id block_id.
+// Amendments to a sealed record are sealed with the same data key as the record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AmendRecordFields {
+    pub block_id: i64,
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<RecordDetails>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentReference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedRecord>,
+}
+
+// Record content encrypted under the record's data key, with that key wrapped for each provider allowed to read it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedRecord {
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<RecordKey>,
+    // Set when the author picked the readers. Only records left readable by every provider are shared with
+    // providers added later; the others need an explicit share-record.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub selected_readers: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// A record's data key wrapped to one provider. The recipient is the provider's node id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordKey {
+    pub recipient: String,
+    pub key: String,
+}
+
+// The part of a record that is sealed under its data key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordContent {
+    pub subject: String,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<RecordDetails>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentReference>,
+}
+
+// Grants providers access to existing records by wrapping each record's data key to them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareRecordsFields {
+    pub grants: Vec<RecordGrant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordGrant {
+    pub block_id: i64,
+    pub recipient: String,
+    pub key: String,
+}
+
+// A file in the attachment store, referenced by the SHA-256 of its contents. The key the file is encrypted with
+// travels in the reference, so only providers that can read the record can open it. References written before
+// attachments had their own keys have none, and their files are encrypted with the chain key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentReference {
+    pub hash: String,
+    pub name: String,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key: String,
+}
+
+// Structured clinical data for a record, tagged by its kind
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum RecordDetails {
+    Observation(ObservationDetails),
+    Medication(MedicationDetails),
+    Allergy(AllergyDetails),
+    Condition(ConditionDetails),
+    Immunization(ImmunizationDetails),
+}
+
+// A vital sign or other measured observation, e.g. code "blood-pressure", value "120/80", unit "mmHg"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObservationDetails {
+    pub code: String,
+    pub value: String,
+    #[serde(default)]
+    pub unit: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MedicationDetails {
+    pub medication: String,
+    #[serde(default)]
+    pub dose: String,
+    #[serde(default)]
+    pub route: String,
+    #[serde(default)]
+    pub frequency: String,
+    #[serde(default)]
+    pub status: MedicationStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MedicationStatus {
+    #[default]
+    Active,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllergyDetails {
+    pub substance: String,
+    #[serde(default)]
+    pub reaction: String,
+    #[serde(default)]
+    pub severity: AllergySeverity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllergySeverity {
+    Mild,
+    Moderate,
+    Severe,
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConditionDetails {
+    pub condition: String,
+    #[serde(default)]
+    pub status: ConditionStatus,
+    #[serde(default)]
+    pub onset: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConditionStatus {
+    #[default]
+    Active,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmunizationDetails {
+    pub vaccine: String,
+    #[serde(default)]
+    pub date: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dose_number: Option<i64>,
+}
+
+impl RecordDetails {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RecordDetails::Observation(_) => "observation",
+            RecordDetails::Medication(_) => "medication",
+            RecordDetails::Allergy(_) => "allergy",
+            RecordDetails::Condition(_) => "condition",
+            RecordDetails::Immunization(_) => "immunization",
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            RecordDetails::Observation(details) => {
+                require_non_empty("code", &details.code)?;
+                require_non_empty("value", &details.value)
+            },
+            RecordDetails::Medication(details) => require_non_empty("medication", &details.medication),
+            RecordDetails::Allergy(details) => require_non_empty("substance", &details.substance),
+            RecordDetails::Condition(details) => require_non_empty("condition", &details.condition),
+            RecordDetails::Immunization(details) => {
+                require_non_empty("vaccine", &details.vaccine)?;
+                match details.dose_number {
+                    Some(dose_number) if dose_number < 1 => Err(format!("Invalid dose number: {}", dose_number)),
+                    _ => Ok(())
+                }
+            },
+        }
+    }
+}
+
+// Marks the add-record block with id block_id as entered in error
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetractRecordFields {
+    pub block_id: i64,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl BlockData {
+    pub fn action(&self) -> &'static str {
+        match self {
+            BlockData::Genesis(_) => "genesis",
+            BlockData::AddProvider(_) => "add-provider",
+            BlockData::RemoveProvider(_) => "remove-provider",
+            BlockData::UpdateProviderAddress(_) => "update-provider-address",
+            BlockData::AddRecord(_) => "add-record",
+            BlockData::AmendRecord(_) => "amend-record",
+            BlockData::RetractRecord(_) => "retract-record",
+            BlockData::ShareRecords(_) => "share-records",
+        }
+    }
+
+    // Checked when a block is created locally and when one is received from a peer
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            BlockData::Genesis(fields) => {
+                require_non_empty("first_name", &fields.first_name)?;
+                require_non_empty("last_name", &fields.last_name)?;
+                require_non_empty("date_of_birth", &fields.date_of_birth)
+            },
+            BlockData::AddProvider(fields) => {
+                require_non_empty("name", &fields.name)?;
+                require_ip(&fields.ip)?;
+                require_public_key(&fields.public_key)
+            },
+            BlockData::RemoveProvider(fields) => if fields.node_id.is_empty() {
+                require_ip(&fields.ip)
+            } else {
+                require_node_id(&fields.node_id)
+            },
+            BlockData::UpdateProviderAddress(fields) => {
+                require_node_id(&fields.node_id)?;
+                require_ip(&fields.ip)
+            },
+            BlockData::AddRecord(fields) => match &fields.sealed {
+                Some(sealed) => {
+                    require_sealed_only(&fields.subject, &fields.text, &fields.details, &fields.attachments)?;
+                    validate_sealed(sealed)?;
+                    if sealed.keys.is_empty() {
+                        return Err("A sealed record must be readable by at least one provider".to_string());
+                    }
+                    Ok(())
+                },
+                None => validate_content(&fields.subject, &fields.details, &fields.attachments)
+            },
+            BlockData::AmendRecord(fields) => {
+                require_block_reference(fields.block_id)?;
+                match &fields.sealed {
+                    Some(sealed) => {
+                        require_sealed_only(&fields.subject, &fields.text, &fields.details, &fields.attachments)?;
+                        validate_sealed(sealed)?;
+                        if !sealed.keys.is_empty() {
+                            return Err("Amendments are sealed with the data key of the record they amend".to_string());
+                        }
+                        Ok(())
+                    },
+                    None => validate_content(&fields.subject, &fields.details, &fields.attachments)
+                }
+            },
+            BlockData::RetractRecord(fields) => require_block_reference(fields.block_id),
+            BlockData::ShareRecords(fields) => {
+                if fields.grants.is_empty() {
+                    return Err("Field grants must not be empty".to_string());
+                }
+                for grant in &fields.grants {
+                    require_block_reference(grant.block_id)?;
+                    require_record_key(&grant.recipient, &grant.key)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// Parse the typed fields for a block out of socket request parameters
+pub fn fields_from_parameters<T: DeserializeOwned>(parameters: &Map<String, Value>) -> Result<T, String> {
+    from_value(Value::Object(parameters.clone())).map_err(|err| format!("Invalid parameters: {}", err))
+}
+
+pub fn string_parameter(parameters: &Map<String, Value>, key: &str) -> Result<String, String> {
+    match parameters.get(key).and_then(Value::as_str) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(format!("Missing or invalid parameter: {}", key))
+    }
+}
+
+pub fn integer_parameter(parameters: &Map<String, Value>, key: &str) -> Result<i64, String> {
+    match parameters.get(key).and_then(Value::as_i64) {
+        Some(value) => Ok(value),
+        None => Err(format!("Missing or invalid parameter: {}", key))
+    }
+}
+
+// Sealed content we hold no key for is never opened, so its content fields stay empty
+pub fn is_unopened(sealed: &Option<SealedRecord>, subject: &str) -> bool {
+    sealed.is_some() && subject.is_empty()
+}
+
+// Checked on record content before it is sealed, and on records written before sealing
+pub fn validate_content(subject: &str, details: &Option<RecordDetails>, attachments: &[AttachmentReference]) -> Result<(), String> {
+    require_non_empty("subject", subject)?;
+    validate_details(details)?;
+    validate_attachments(attachments)
+}
+
+fn validate_sealed(sealed: &SealedRecord) -> Result<(), String> {
+    if sealed.content.is_empty() || sealed.content.from_hex().is_err() {
+        return Err("Sealed record content must be hex encoded".to_string());
+    }
+    for record_key in &sealed.keys {
+        require_record_key(&record_key.recipient, &record_key.key)?;
+    }
+    Ok(())
+}
+
+// Content travels inside the sealed record, so none of it may also appear in the clear
+fn require_sealed_only(subject: &str, text: &str, details: &Option<RecordDetails>, attachments: &[AttachmentReference]) -> Result<(), String> {
+    if !subject.is_empty() || !text.is_empty() || details.is_some() || !attachments.is_empty() {
+        return Err("Sealed records must not carry unsealed content".to_string());
+    }
+    Ok(())
+}
+
+fn require_node_id(node_id: &str) -> Result<(), String> {
+    if !is_hash(node_id) {
+        return Err(format!("Invalid node id: {}", node_id));
+    }
+    Ok(())
+}
+
+fn require_record_key(recipient: &str, key: &str) -> Result<(), String> {
+    if !is_hash(recipient) {
+        return Err(format!("Invalid key recipient: {}", recipient));
+    }
+    if key.is_empty() || key.from_hex().is_err() {
+        return Err("Wrapped record keys must be hex encoded".to_string());
+    }
+    Ok(())
+}
+
+fn validate_details(details: &Option<RecordDetails>) -> Result<(), String> {
+    match details {
+        Some(details) => details.validate(),
+        None => Ok(())
+    }
+}
+
+fn validate_attachments(attachments: &[AttachmentReference]) -> Result<(), String> {
+    for attachment in attachments {
+        if !is_hash(&attachment.hash) {
+            return Err(format!("Invalid attachment hash: {}", attachment.hash));
+        }
+        require_non_empty("attachment name", &attachment.name)?;
+        if !attachment.key.is_empty() && attachment.key.from_hex().map(|key| key.len()).ok() != Some(32) {
+            return Err(format!("Invalid key for attachment {}", attachment.hash));
+        }
+    }
+    Ok(())
+}
+
+fn require_non_empty(name: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("Field {} must not be empty", name));
+    }
+    Ok(())
+}
+
+fn require_block_reference(block_id: i64) -> Result<(), String> {
+    if block_id < 0 {
+        return Err(format!("Invalid block reference: {}", block_id));
+    }
+    Ok(())
+}
+
+fn require_public_key(value: &str) -> Result<(), String> {
+    match PKey::public_key_from_pem(value.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Field public_key must be a PEM encoded public key".to_string())
+    }
+}
+
+fn require_ip(value: &str) -> Result<(), String> {
+    match value.parse::<IpAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Invalid IP address: {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn block_data(value: Value) -> BlockData {
+        from_value(value).unwrap()
+    }
+
+    fn add_record() -> BlockData {
+        block_data(json!({"action": "add-record", "fields": {"subject": "Visit", "text": "Notes"}}))
+    }
+
+    fn share_records() -> BlockData {
+        block_data(json!({"action": "share-records", "fields": {"grants": []}}))
+    }
+
+    fn add_provider() -> BlockData {
+        block_data(json!({"action": "add-provider", "fields": {"name": "Provider", "ip": "10.0.0.1"}}))
+    }
+
+    #[test]
+    fn readers_can_append_nothing() {
+        for data in [add_record(), share_records(), add_provider()] {
+            assert!(!ProviderRole::Reader.can_append(&data, ProviderRole::Reader));
+        }
+    }
+
+    #[test]
+    fn contributors_can_only_append_records() {
+        assert!(ProviderRole::Contributor.can_append(&add_record(), ProviderRole::Contributor));
+        assert!(ProviderRole::Contributor.can_append(&block_data(json!({"action": "retract-record", "fields": {"block_id": 3}})), ProviderRole::Contributor));
+        assert!(!ProviderRole::Contributor.can_append(&share_records(), ProviderRole::Contributor));
+        assert!(!ProviderRole::Contributor.can_append(&add_provider(), ProviderRole::Reader));
+    }
+
+    #[test]
+    fn administrators_manage_providers_other_than_owners() {
+        let administrator = ProviderRole::Administrator;
+        assert!(administrator.can_append(&share_records(), ProviderRole::Contributor));
+        assert!(administrator.can_append(&add_provider(), ProviderRole::Administrator));
+        assert!(!administrator.can_append(&add_provider(), ProviderRole::Owner));
+        let remove_provider = block_data(json!({"action": "remove-provider", "fields": {"node_id": "id"}}));
+        assert!(administrator.can_append(&remove_provider, ProviderRole::Contributor));
+        assert!(!administrator.can_append(&remove_provider, ProviderRole::Owner));
+    }
+
+    #[test]
+    fn owners_can_append_anything_but_a_genesis_block() {
+        assert!(ProviderRole::Owner.can_append(&add_provider(), ProviderRole::Owner));
+        assert!(ProviderRole::Owner.can_append(&add_record(), ProviderRole::Contributor));
+        let genesis = block_data(json!({"action": "genesis", "fields": {"first_name": "Ann", "last_name": "Lee", "date_of_birth": "1990-01-01"}}));
+        assert!(!ProviderRole::Owner.can_append(&genesis, ProviderRole::Owner));
+    }
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(ProviderRole::Reader < ProviderRole::Contributor);
+        assert!(ProviderRole::Contributor < ProviderRole::Administrator);
+        assert!(ProviderRole::Administrator < ProviderRole::Owner);
+        assert_eq!(ProviderRole::parse("administrator"), Some(ProviderRole::Administrator));
+        assert_eq!(ProviderRole::parse("admin"), None);
+    }
+
+    #[test]
+    fn attachment_keys_must_be_32_bytes_of_hex() {
+        let mut attachment = AttachmentReference{ hash: "a".repeat(64), name: "scan.pdf".to_string(), mime_type: String::new(), size: 0, key: String::new() };
+        assert!(validate_attachments(std::slice::from_ref(&attachment)).is_ok());
+        attachment.key = "ab".repeat(32);
+        assert!(validate_attachments(std::slice::from_ref(&attachment)).is_ok());
+        attachment.key = "ab".repeat(16);
+        assert!(validate_attachments(std::slice::from_ref(&attachment)).is_err());
+        attachment.key = "zz".repeat(32);
+        assert!(validate_attachments(std::slice::from_ref(&attachment)).is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+use std::{fs, os::unix::fs::PermissionsExt};
 use dirs::home_dir;
 
-use serde_json::{from_str, to_string, Map, Value};
+use serde_json::{from_str, json, to_string, Deserializer, Map, Value};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{UnixListener, UnixStream}};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -27,7 +27,7 @@ pub async fn initialize_socket_thread(receiver_from_blockchain: Receiver<String>
     
     let home = home_dir().unwrap();
     
-    let mut sock_dir = PathBuf::from(home.clone());
+    let mut sock_dir = home.clone();
     sock_dir.push(UNIX_SOCKET_DOMAIN_DIR);
     if !sock_dir.exists() {
         fs::create_dir_all(sock_dir.clone()).unwrap();
@@ -56,28 +56,49 @@ pub async fn initialize_socket_thread(receiver_from_blockchain: Receiver<String>
 }
 
 async fn handle_read_from_client(mut stream: UnixStream, mut receiver_from_blockchain: Receiver<String>, sender_to_blockchain: Sender<String>, listener: UnixListener) {
+    // Requests such as attachment uploads can span several reads, so bytes accumulate until a full request parses
+    let mut pending: Vec<u8> = Vec::new();
     loop {
         let mut buffer = vec![0; 1024];
 
         match stream.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
-                match listener.accept().await {
-                    Ok((new_stream, _)) => {
-                        stream = new_stream;
-                        continue;
-                    }
-                    Err(_) => {},
-                };
+            Ok(0) => {
+                pending.clear();
+                if let Ok((new_stream, _)) = listener.accept().await {
+                    stream = new_stream;
+                    continue;
+                }
             }
             Ok(n) => {
-                let received_data = String::from_utf8_lossy(&buffer[..n]);
-                //println!("{}", received_data);
-                let request: SocketRequest = from_str(&received_data).unwrap();
-                let action: &str = &request.action;
-                let parameters = &request.parameters;
-                let response = request_blockchain(request.id, action.to_string(), parameters, &mut receiver_from_blockchain, sender_to_blockchain.clone()).await;
-                let response_json = to_string(&response).unwrap();
-                stream.write_all(response_json.as_bytes()).await.unwrap();
+                pending.extend_from_slice(&buffer[..n]);
+                let mut requests = Deserializer::from_slice(&pending).into_iter::<SocketRequest>();
+                let mut complete: Vec<SocketRequest> = Vec::new();
+                let mut malformed = false;
+                for request in requests.by_ref() {
+                    match request {
+                        Ok(request) => complete.push(request),
+                        Err(err) if err.is_eof() => break,
+                        Err(err) => {
+                            eprintln!("Ignoring malformed socket request: {}", err);
+                            malformed = true;
+                            break;
+                        }
+                    }
+                }
+                let consumed = requests.byte_offset();
+                if malformed {
+                    pending.clear();
+                } else {
+                    pending.drain(..consumed);
+                }
+
+                for request in complete {
+                    let action: &str = &request.action;
+                    let parameters = &request.parameters;
+                    let response = request_blockchain(request.id, action.to_string(), parameters, &mut receiver_from_blockchain, sender_to_blockchain.clone()).await;
+                    let response_json = to_string(&response).unwrap();
+                    stream.write_all(response_json.as_bytes()).await.unwrap();
+                }
             }
             Err(_) => {}
         }
@@ -85,7 +106,7 @@ async fn handle_read_from_client(mut stream: UnixStream, mut receiver_from_block
 }
 
 async fn request_blockchain(request_id: i64, action: String, parameters: &Map<String, Value>, receiver_from_blockchain: &mut Receiver<String>, sender_to_blockchain: Sender<String>) -> SocketResponse {
-    sender_to_blockchain.send(to_string(&BlockchainRequest{action: action, parameters: parameters.clone(), sender: "socket".to_string() }).unwrap()).await.unwrap();
+    sender_to_blockchain.send(to_string(&BlockchainRequest{action, parameters: parameters.clone(), sender: "socket".to_string() }).unwrap()).await.unwrap();
     let mut response = SocketResponse{id: request_id, data: "".to_string()};
 
     loop {
@@ -93,6 +114,8 @@ async fn request_blockchain(request_id: i64, action: String, parameters: &Map<St
             let blockchain_response: BlockchainResponse = from_str(&msg).unwrap();
             if blockchain_response.ok {
                 response.data = blockchain_response.data.to_string();
+            } else if let Value::String(message) = blockchain_response.data {
+                response.data = json!({"error": message}).to_string();
             } else {
                 response.data = "{}".to_string();
             }
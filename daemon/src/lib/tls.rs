@@ -0,0 +1,270 @@
+use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc};
+use dirs::home_dir;
+use openssl::{asn1::Asn1Time, bn::{BigNum, MsbOption}, hash::MessageDigest, nid::Nid, pkey::PKey, x509::{X509, X509NameBuilder}};
+use rustls::{client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, crypto::{aws_lc_rs::default_provider, verify_tls12_signature, verify_tls13_signature}, pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName, UnixTime}, server::{danger::{ClientCertVerified, ClientCertVerifier}, WebPkiClientVerifier}, CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, SignatureScheme};
+use crate::{blockchain::{der_key_id, key_id, my_node_id}, database::{get_key_pair, KeyPair}};
+
+// Node certificates are only a carrier for the node's persistent key pair, so they are long lived
+const NODE_CERTIFICATE_DAYS: u32 = 3650;
+
+// Certificate authority trust mode is switched on by placing the authority's certificates in this
+// directory, next to the socket. The node certificate and key it issued to us sit alongside, with an
+// optional revocation list.
+const TRUST_DIR: &str = ".ehr/ca/";
+const CA_BUNDLE_FILE: &str = "ca.pem";
+const NODE_CERTIFICATE_FILE: &str = "node.pem";
+const NODE_KEY_FILE: &str = "node.key";
+const CRL_FILE: &str = "crl.pem";
+
+// How peers decide to trust each other. Self-signed certificates carry each node's own key, so the node
+// id is the hash of the certificate's key. A certificate authority instead vouches for the node id
+// written in the common name of the certificates it issues.
+pub enum TrustMode {
+    SelfSigned,
+    Authority(AuthorityTrust),
+}
+
+pub struct AuthorityTrust {
+    // Checks a certificate chains to the authority and has not been revoked
+    verifier: Arc<dyn ClientCertVerifier>,
+    certificates: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+}
+
+// Files are read once for every connection, so a replaced revocation list applies to the next one
+pub fn trust_mode() -> Result<TrustMode, String> {
+    let directory = match home_dir() {
+        Some(mut directory) => {
+            directory.push(TRUST_DIR);
+            directory
+        },
+        None => return Ok(TrustMode::SelfSigned)
+    };
+    if !directory.join(CA_BUNDLE_FILE).exists() {
+        return Ok(TrustMode::SelfSigned);
+    }
+
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(&directory.join(CA_BUNDLE_FILE))? {
+        roots.add(certificate).map_err(|err| format!("Invalid certificate in {}: {}", CA_BUNDLE_FILE, err))?;
+    }
+    let crls: Vec<CertificateRevocationListDer<'static>> = match File::open(directory.join(CRL_FILE)) {
+        Ok(file) => rustls_pemfile::crls(&mut BufReader::new(file)).collect::<Result<_, _>>().map_err(|err| format!("Invalid {}: {}", CRL_FILE, err))?,
+        Err(_) => vec![]
+    };
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .with_crls(crls)
+        .only_check_end_entity_revocation()
+        .build()
+        .map_err(|err| format!("Unable to load {}: {}", CA_BUNDLE_FILE, err))?;
+
+    let certificates = read_certificates(&directory.join(NODE_CERTIFICATE_FILE))?;
+    let private_key = match File::open(directory.join(NODE_KEY_FILE)).map(|file| rustls_pemfile::private_key(&mut BufReader::new(file))) {
+        Ok(Ok(Some(private_key))) => private_key,
+        _ => return Err(format!("Unable to read {}", NODE_KEY_FILE))
+    };
+
+    // Peers pin us to the node id our block signing key gives us, so the authority must have issued our certificate for it
+    match certificates.first().and_then(certificate_common_name) {
+        Some(node_id) if node_id == my_node_id() => {},
+        _ => return Err(format!("The common name in {} must be this node's id, {}", NODE_CERTIFICATE_FILE, my_node_id()))
+    }
+
+    Ok(TrustMode::Authority(AuthorityTrust{ verifier, certificates, private_key }))
+}
+
+fn read_certificates(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|_| format!("Unable to read {}", path.display()))?;
+    let certificates: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<_, _>>()
+        .map_err(|err| format!("Invalid {}: {}", path.display(), err))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn certificate_common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let certificate = X509::from_der(certificate).ok()?;
+    let entry = certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    std::str::from_utf8(entry.data().as_slice()).ok().map(str::to_string)
+}
+
+// Our TLS certificate, self-signed with the same key pair we sign blocks with, so the certificate's
+// public key is the one recorded for us on every chain
+fn node_certificate(key_pair: &KeyPair) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), String> {
+    let build = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let pkey = PKey::private_key_from_pkcs8(&key_pair.private_key)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", &key_id(&key_pair.public_key))?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(serial.to_asn1_integer()?.as_ref())?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&pkey)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(Asn1Time::days_from_now(NODE_CERTIFICATE_DAYS)?.as_ref())?;
+        builder.sign(&pkey, MessageDigest::sha256())?;
+        builder.build().to_der()
+    };
+    match build() {
+        Ok(der) => Ok((CertificateDer::from(der), PrivateKeyDer::Pkcs8(key_pair.private_key.clone().into()))),
+        Err(err) => Err(format!("Unable to create node certificate: {}", err))
+    }
+}
+
+fn our_certificate() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), String> {
+    match get_key_pair() {
+        Ok(Some(key_pair)) => node_certificate(&key_pair),
+        _ => Err("No node key pair".to_string())
+    }
+}
+
+// Node id of the key in a peer's certificate, which is the id the same key is recorded under on chains
+fn certificate_node_id(certificate: &CertificateDer<'_>) -> Option<String> {
+    let der = X509::from_der(certificate).ok()?.public_key().ok()?.public_key_to_der().ok()?;
+    Some(der_key_id(&der))
+}
+
+// Node id of the peer on the other end of an established connection, whose certificate has already been verified
+// under the same trust mode
+pub fn peer_node_id(trust: &TrustMode, certificates: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let certificate = certificates?.first()?;
+    match trust {
+        TrustMode::Authority(_) => certificate_common_name(certificate),
+        TrustMode::SelfSigned => certificate_node_id(certificate)
+    }
+}
+
+// Every node listens with its own certificate and requires one from the connecting node
+pub fn server_config(trust: &TrustMode) -> Result<ServerConfig, String> {
+    let builder = ServerConfig::builder();
+    let result = match trust {
+        TrustMode::SelfSigned => {
+            let (certificate, private_key) = our_certificate()?;
+            builder.with_client_cert_verifier(Arc::new(NodeClientVerifier)).with_single_cert(vec![certificate], private_key)
+        },
+        TrustMode::Authority(trust) => builder.with_client_cert_verifier(trust.verifier.clone()).with_single_cert(trust.certificates.clone(), trust.private_key.clone_key())
+    };
+    result.map_err(|err| err.to_string())
+}
+
+// Connections to a provider are pinned to its node id from the chain. Only a first contact, where we are
+// asking a node for its public key, goes unpinned.
+pub fn client_config(trust: &TrustMode, node_id: Option<&str>) -> Result<ClientConfig, String> {
+    let node_id = node_id.map(str::to_string);
+    let builder = ClientConfig::builder().dangerous();
+    let result = match trust {
+        TrustMode::SelfSigned => {
+            let (certificate, private_key) = our_certificate()?;
+            builder.with_custom_certificate_verifier(Arc::new(PinnedServerVerifier{ node_id }))
+                .with_client_auth_cert(vec![certificate], private_key)
+        },
+        TrustMode::Authority(trust) => builder.with_custom_certificate_verifier(Arc::new(AuthorityServerVerifier{ verifier: trust.verifier.clone(), node_id }))
+            .with_client_auth_cert(trust.certificates.clone(), trust.private_key.clone_key())
+    };
+    result.map_err(|err| err.to_string())
+}
+
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    node_id: Option<String>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let peer_node_id = match certificate_node_id(end_entity) {
+            Some(node_id) => node_id,
+            None => return Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))
+        };
+        match &self.node_id {
+            Some(node_id) if *node_id != peer_node_id => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+            _ => Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, certificate, signature, &default_provider().signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, certificate, signature, &default_provider().signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        default_provider().signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Any node may connect, as long as it proves it holds the key in its certificate. What it may do is
+// decided per request from the node id that key gives it.
+#[derive(Debug)]
+struct NodeClientVerifier;
+
+impl ClientCertVerifier for NodeClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, end_entity: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        match certificate_node_id(end_entity) {
+            Some(_) => Ok(ClientCertVerified::assertion()),
+            None => Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, certificate, signature, &default_provider().signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, certificate, signature, &default_provider().signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        default_provider().signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Nodes are named by node id rather than host name, so a server's certificate is checked the same way a
+// connecting node's is: it must chain to the authority, not be revoked, and name the node we expect.
+// Node certificates therefore need to allow both client and server authentication.
+struct AuthorityServerVerifier {
+    verifier: Arc<dyn ClientCertVerifier>,
+    node_id: Option<String>,
+}
+
+impl std::fmt::Debug for AuthorityServerVerifier {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.debug_struct("AuthorityServerVerifier").field("node_id", &self.node_id).finish()
+    }
+}
+
+impl ServerCertVerifier for AuthorityServerVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier.verify_client_cert(end_entity, intermediates, now)?;
+        match (&self.node_id, certificate_common_name(end_entity)) {
+            (Some(node_id), Some(common_name)) if *node_id != common_name => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+            (_, None) => Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding)),
+            _ => Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
@@ -1,4 +1,4 @@
-use internal_lib::blockchain::initialize_blockchain_thread;
+use internal_lib::blockchain::{initialize_blockchain_thread, rebuild_stale_projections, verify_all_chains};
 use internal_lib::socket::initialize_socket_thread; 
 use internal_lib::{database, network::initialize_p2p_thread };
 use tokio::sync::mpsc::channel;
@@ -9,6 +9,12 @@ async fn main() {
     // Connect to local database and bootstrap tables if this is first launch
     let _ = database::bootstrap();
 
+    // Check every local chain for tampering or corruption before serving any data
+    verify_all_chains();
+
+    // Bring the patient-state projection up to date for chains stored before it existed
+    rebuild_stale_projections();
+
     // Create channels for communication between threads
     let (socket_tx, socket_rx) = channel(10);
     let (blockchain_tx, blockchain_rx) = channel(10);